@@ -1,7 +1,6 @@
 extends Node
 
 var gen: TextGenerator
-var recv: TextReceiver
 var tokens: Array[String] = []
 var generating = false
 var finished = false
@@ -11,7 +10,7 @@ var thread: Thread
 var loader_thread: Thread
 
 var model_id = "karpathy/tinyllamas"
-var which_model = "stories15m.bin"
+var which_model = "stories15M.bin"
 var tokenizer_id = "hf-internal-testing/llama-tokenizer"
 
 # Called when the node enters the scene tree for the first time.
@@ -19,6 +18,11 @@ func _ready() -> void:
 	thread = Thread.new()
 	gen = TextGenerator.new()
 	gen.loaded.connect(_on_model_loaded)
+	gen.token.connect(_on_token)
+	gen.finished.connect(_on_finished)
+	# The model runs on its own thread, pausing it for 5 ms every 50 ms leaves the
+	# cores to the render and physics threads
+	gen.set_cooperative_yield(50, 5)
 	loader_thread = Thread.new()
 	loader_thread.start(gen.load_model.bind(model_id, which_model, tokenizer_id))
 	
@@ -26,7 +30,7 @@ func _on_model_loaded() -> void:
 	print_debug("Model loaded")
 	loaded = true
 
-func _on_token(token: String) -> void:
+func _on_token(token: String, logprobs: Array) -> void:
 	tokens.append(token)
 	print(token)
 
@@ -37,25 +41,15 @@ func _on_finished() -> void:
 	# unload the generator here if you want or keep it in memory for more generations using prompt model
 	# gen.unload()
 
-func _on_disconnected() -> void:
-	print_debug("Receiver disconnected!")
-	get_tree().quit()
-
 # Called every frame. 'delta' is the elapsed time since the previous frame.
 func _process(delta: float) -> void:
-	if loaded:
-		if not prompted:
-			var prompt = "Once upon a time"
-			print_debug("Prompting model: ", prompt)
-			recv = gen.prompt(prompt)
-			recv.token.connect(_on_token)
-			recv.finished.connect(_on_finished)
-			recv.disconnected.connect(_on_disconnected)
-			print_debug(recv)
-			prompted = true
-		# poll for a token
-		if recv:
-			recv.poll() # tokens can be received via a signal
+	if loaded and not prompted:
+		var prompt = "Once upon a time"
+		print_debug("Prompting model: ", prompt)
+		# tokens are delivered through the token signal while the model runs on its own thread
+		# the story model is not a chat model, so the prompt is continued as it is
+		thread.start(gen.complete.bind(prompt, 256, 1.1, 64))
+		prompted = true
 
 func _exit_tree() -> void:
 	loader_thread.wait_to_finish()
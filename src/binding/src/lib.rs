@@ -1,14 +1,30 @@
 use anyhow::{Error as E, Result};
-use candle_core::Tensor;
+use candle_core::{DType, Tensor};
 use godot::engine::IObject;
+use godot::engine::Json;
 use godot::engine::Object;
+use godot::engine::ProjectSettings;
 use godot::obj::WithBaseField;
 use godot::prelude::*;
 use inference::embedding::EmbeddingModel;
+use inference::generation::{
+    CancelHandle, CooperativeYield, Generation, GenerationConfig, GenerationSummary,
+};
+use inference::grammar::Grammar;
+use inference::lora::LoraAdapter;
+use inference::memory::MemoryEstimate;
+use inference::prompts::{ChatFormat, ChatMessage, ChatTemplate, Conversation, HistoryOverflow};
+use inference::sampling::{SamplingConfig, TokenLogprobs};
+use inference::session::{ContextOverflow, PrefixCache, Session};
+use inference::source::{ModelFiles, ModelSource};
 use inference::text_generation::TextGeneration;
+use inference::tools::{self, Tool, ToolCall, TOOL_CALL_END, TOOL_CALL_START};
 use std::borrow::BorrowMut;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[gdextension]
 unsafe impl ExtensionLibrary for Jovia {}
@@ -18,6 +34,27 @@ unsafe impl ExtensionLibrary for Jovia {}
 pub struct TextGenerator {
     base: Base<Object>,
     pipeline: RefCell<Option<TextGeneration>>,
+    session: Option<Session>,
+    /// The messages of the chat held in `session`, starting with the prefix given to start_chat.
+    chat_messages: Vec<ChatMessage>,
+    prefix_cache: PrefixCache,
+    sampling: Option<SamplingConfig>,
+    stop_sequences: Vec<String>,
+    logit_bias: HashMap<u32, f32>,
+    banned_words: Vec<String>,
+    grammar: Option<Arc<Grammar>>,
+    logprobs: Option<usize>,
+    context_overflow: ContextOverflow,
+    cancel: CancelHandle,
+    time_limit: Option<Duration>,
+    threads: Option<usize>,
+    cooperative_yield: Option<CooperativeYield>,
+    last_summary: Option<GenerationSummary>,
+    loras: HashMap<String, LoraAdapter>,
+    conversation: Option<Conversation>,
+    /// The session say runs the conversation in, holding the KV cache of its turns.
+    conversation_session: Option<Session>,
+    tools: Vec<(Tool, Callable)>,
     tokens: Vec<String>,
     rx: Option<Receiver<String>>,
 }
@@ -28,6 +65,25 @@ impl IObject for TextGenerator {
         Self {
             base,
             pipeline: RefCell::new(None),
+            session: None,
+            chat_messages: Vec::new(),
+            prefix_cache: PrefixCache::new(),
+            sampling: None,
+            stop_sequences: Vec::new(),
+            logit_bias: HashMap::new(),
+            banned_words: Vec::new(),
+            grammar: None,
+            logprobs: None,
+            context_overflow: ContextOverflow::Error,
+            cancel: CancelHandle::new(),
+            time_limit: None,
+            threads: None,
+            cooperative_yield: None,
+            last_summary: None,
+            loras: HashMap::new(),
+            conversation: None,
+            conversation_session: None,
+            tools: Vec::new(),
             rx: None,
             tokens: Vec::new(),
         }
@@ -40,18 +96,437 @@ impl TextGenerator {
     pub fn loaded();
 
     #[signal]
-    pub fn token(token: String);
+    pub fn token(token: String, logprobs: Array<Dictionary>);
 
     #[signal]
     pub fn finished();
 
+    #[signal]
+    pub fn tool_called(name: String, arguments: Dictionary, result: Variant);
+
+    #[signal]
+    pub fn error(message: String);
+
+    #[func]
+    /// Loads `which_model` from the `model_id` hub repository.
+    /// `tokenizer_id` names the hub repository holding `tokenizer.json`, pass an empty string when
+    /// it is the same as `model_id`.
+    pub fn load_model(&mut self, model_id: String, which_model: String, tokenizer_id: String) {
+        let tokenizer_id = Some(tokenizer_id).filter(|id| !id.is_empty());
+        let mut pipeline =
+            TextGeneration::new(model_id, which_model, tokenizer_id, None, None, None, None)
+                .unwrap();
+        pipeline.set_threads(self.threads).unwrap();
+        // Models without a chat template get the Zephyr format prompt always used
+        if pipeline.chat_template.is_none() {
+            pipeline.chat_template = Some(ChatFormat::Zephyr.template());
+        }
+        self.pipeline = RefCell::new(Some(pipeline));
+        // Sessions hold KV caches of the previous model, the bias and adapters are made for it
+        self.session = None;
+        self.chat_messages.clear();
+        self.conversation_session = None;
+        self.prefix_cache.clear();
+        self.logit_bias.clear();
+        self.loras.clear();
+        self.base_mut().emit_signal("loaded".into(), &[]);
+    }
+
     #[func]
-    pub fn load_model(&mut self, model_id: String, which_model: String) {
-        let pipeline = TextGeneration::new(model_id, which_model, None, None, None, None).unwrap();
+    /// Loads a model from a local directory laid out like a hub repository.
+    /// This never touches the network so it is the way to load models shipped with a game.
+    pub fn load_local_model(&mut self, model_dir: GString, which_model: String) {
+        let model_dir = ProjectSettings::singleton().globalize_path(model_dir);
+        let mut pipeline =
+            TextGeneration::from_local(model_dir.to_string(), which_model, None, None, None)
+                .unwrap();
+        pipeline.set_threads(self.threads).unwrap();
+        // Models without a chat template get the Zephyr format prompt always used
+        if pipeline.chat_template.is_none() {
+            pipeline.chat_template = Some(ChatFormat::Zephyr.template());
+        }
         self.pipeline = RefCell::new(Some(pipeline));
+        // Sessions hold KV caches of the previous model, the bias and adapters are made for it
+        self.session = None;
+        self.chat_messages.clear();
+        self.conversation_session = None;
+        self.prefix_cache.clear();
+        self.logit_bias.clear();
+        self.loras.clear();
         self.base_mut().emit_signal("loaded".into(), &[]);
     }
 
+    #[func]
+    /// Frees the loaded model along with the conversations, draft model and LoRA adapters that
+    /// belong to it. Settings such as the sampling are kept for the next model.
+    pub fn unload(&mut self) {
+        self.pipeline = RefCell::new(None);
+        self.session = None;
+        self.chat_messages.clear();
+        self.conversation_session = None;
+        self.prefix_cache.clear();
+        self.logit_bias.clear();
+        self.loras.clear();
+        self.last_summary = None;
+    }
+
+    #[func]
+    /// Estimates the memory `which_model` from the `model_id` hub repository takes once loaded
+    /// with load_model, so that a model fitting the player's machine can be picked first. Returns
+    /// "weights", "kv_cache" and "total" in bytes along with the "context_length" the KV cache
+    /// was estimated for, the model's whole context window when `context_length` is zero or
+    /// less. Safetensors models only download their config for this.
+    pub fn estimate_memory(
+        model_id: String,
+        which_model: String,
+        context_length: i64,
+    ) -> Dictionary {
+        let source = ModelSource::hub(model_id, None).unwrap();
+        memory_dictionary(&source, &which_model, context_length)
+    }
+
+    #[func]
+    /// Like estimate_memory for a model directory loaded with load_local_model.
+    pub fn estimate_local_memory(
+        model_dir: GString,
+        which_model: String,
+        context_length: i64,
+    ) -> Dictionary {
+        let model_dir = ProjectSettings::singleton().globalize_path(model_dir);
+        let source = ModelSource::local(model_dir.to_string()).unwrap();
+        memory_dictionary(&source, &which_model, context_length)
+    }
+
+    #[func]
+    /// Loads `which_model` from the `model_id` hub repository as the draft model of the loaded
+    /// model. A small draft model sharing the loaded model's tokenizer speeds up generation
+    /// without changing its output. `tokenizer_id` works as in load_model. Both models must be
+    /// safetensors Llama or StableLM models, or llama2.c checkpoints.
+    pub fn load_draft_model(
+        &mut self,
+        model_id: String,
+        which_model: String,
+        tokenizer_id: String,
+    ) {
+        let source = ModelSource::hub(model_id, None).unwrap();
+        let files = if tokenizer_id.is_empty() {
+            ModelFiles::from_source(&source, &which_model)
+        } else {
+            let tokenizer_source = ModelSource::hub(tokenizer_id, None).unwrap();
+            ModelFiles::from_sources(&source, &tokenizer_source, &which_model)
+        }
+        .unwrap();
+        let mut pipeline = self.pipeline.borrow_mut();
+        let pipeline = pipeline.as_mut().expect("no model loaded");
+        pipeline.load_draft_model(&files).unwrap();
+        // Only sessions started from now on use the draft model
+        self.session = None;
+        self.chat_messages.clear();
+        self.conversation_session = None;
+        self.prefix_cache.clear();
+    }
+
+    #[func]
+    /// Stops using the draft model loaded by load_draft_model.
+    pub fn unload_draft_model(&mut self) {
+        if let Some(pipeline) = self.pipeline.borrow_mut().as_mut() {
+            pipeline.unload_draft_model();
+        }
+        self.session = None;
+        self.chat_messages.clear();
+        self.conversation_session = None;
+        self.prefix_cache.clear();
+    }
+
+    #[func]
+    /// Loads the PEFT LoRA adapter of the `adapter_id` hub repository under `name`, see use_lora.
+    pub fn load_lora(&mut self, name: String, adapter_id: String) {
+        let source = ModelSource::hub(adapter_id, None).unwrap();
+        self.add_lora(name, &source);
+    }
+
+    #[func]
+    /// Loads a PEFT LoRA adapter from a local directory under `name`, see use_lora.
+    pub fn load_local_lora(&mut self, name: String, adapter_dir: GString) {
+        let adapter_dir = ProjectSettings::singleton().globalize_path(adapter_dir);
+        let source = ModelSource::local(adapter_dir.to_string()).unwrap();
+        self.add_lora(name, &source);
+    }
+
+    #[func]
+    /// Switches the model to the LoRA adapter loaded under `name`, or back to the base model when
+    /// `name` is empty. Adapters are swapped without reloading the model, `merge` adds the adapter
+    /// to the weights for faster generation at the cost of some memory. The current chat keeps
+    /// the adapter it was started with until start_chat or reset_chat is called.
+    pub fn use_lora(&mut self, name: String, merge: bool) {
+        let mut pipeline = self.pipeline.borrow_mut();
+        let pipeline = pipeline.as_mut().expect("no model loaded");
+        if name.is_empty() {
+            pipeline.remove_lora().unwrap();
+        } else {
+            let adapter = self
+                .loras
+                .get(&name)
+                .expect("no LoRA adapter loaded with this name");
+            pipeline.apply_lora(adapter, merge).unwrap();
+        }
+        // Cached prefixes and the conversation were run with the previous weights
+        self.conversation_session = None;
+        self.prefix_cache.clear();
+    }
+
+    #[func]
+    /// Limits the model to `threads` CPU threads so that cores stay free for rendering and
+    /// physics, zero or less lets it use every core. Applies to prompt calls and to chats started
+    /// afterwards, and carries over to models loaded later. The limit covers the model's
+    /// calculations on the CPU, while picking the tokens runs on the thread calling the
+    /// generator.
+    pub fn set_threads(&mut self, threads: i64) {
+        self.threads = usize::try_from(threads).ok().filter(|threads| *threads > 0);
+        if let Some(pipeline) = self.pipeline.borrow_mut().as_mut() {
+            pipeline.set_threads(self.threads).unwrap();
+        }
+        // Cached prefixes would hand their threads to the chats forked from them
+        self.prefix_cache.clear();
+    }
+
+    #[func]
+    /// Makes the following prompt, chat and say calls sleep for `pause_ms` milliseconds every
+    /// `every_ms` milliseconds of generation, leaving the CPU to the game's other threads on a
+    /// busy machine. This only helps when the generation runs on a worker Thread, as in the
+    /// examples/text_generation project, as pausing the main thread only delays its frame
+    /// further. An `every_ms` of zero or less turns this off again.
+    pub fn set_cooperative_yield(&mut self, every_ms: i64, pause_ms: i64) {
+        self.cooperative_yield = u64::try_from(every_ms)
+            .ok()
+            .filter(|every_ms| *every_ms > 0)
+            .map(|every_ms| {
+                CooperativeYield::new(
+                    Duration::from_millis(every_ms),
+                    Duration::from_millis(pause_ms.max(0) as u64),
+                )
+            });
+    }
+
+    #[func]
+    /// The share of draft model tokens accepted during the last prompt or chat call, or zero
+    /// when no draft model was used.
+    pub fn draft_acceptance_rate(&self) -> f64 {
+        self.last_summary
+            .as_ref()
+            .and_then(|summary| summary.speculative.as_ref())
+            .map_or(0.0, |stats| stats.acceptance_rate())
+    }
+
+    #[func]
+    /// Statistics of the last prompt or chat call: "stop_reason", "prompt_tokens",
+    /// "generated_tokens", "time_to_first_token", "prefill_time" and "decode_time" in seconds,
+    /// and "decode_tokens_per_second". Empty before the first call.
+    pub fn get_stats(&self) -> Dictionary {
+        let mut stats = Dictionary::new();
+        if let Some(summary) = &self.last_summary {
+            let seconds = |time: Duration| time.as_secs_f64();
+            stats.insert("stop_reason", summary.stop_reason.as_str());
+            stats.insert("prompt_tokens", summary.prompt_tokens as i64);
+            stats.insert("generated_tokens", summary.generated_tokens as i64);
+            let time_to_first_token = summary.time_to_first_token.map_or(0.0, seconds);
+            stats.insert("time_to_first_token", time_to_first_token);
+            stats.insert("prefill_time", seconds(summary.prefill_time));
+            stats.insert("decode_time", seconds(summary.decode_time));
+            stats.insert(
+                "decode_tokens_per_second",
+                summary.decode_tokens_per_second(),
+            );
+        }
+        stats
+    }
+
+    #[func]
+    /// Sets the sampling used by the following prompt and chat calls.
+    /// A temperature of zero or less always picks the most likely token. Filters given a value of
+    /// zero or less are disabled.
+    pub fn set_sampling(
+        &mut self,
+        seed: i64,
+        temperature: f64,
+        top_k: i64,
+        top_p: f64,
+        min_p: f64,
+        typical_p: f64,
+    ) {
+        let enabled = |value: f64| Some(value).filter(|value| *value > 0.0);
+        self.sampling = Some(SamplingConfig {
+            seed: seed as u64,
+            greedy: temperature <= 0.0,
+            temperature,
+            top_k: Some(top_k).filter(|k| *k > 0).map(|k| k as usize),
+            top_p: enabled(top_p),
+            min_p: enabled(min_p),
+            typical_p: enabled(typical_p),
+        });
+    }
+
+    #[func]
+    /// Sets strings that end the following prompt and chat calls when the model writes them.
+    /// The stop sequence itself is not emitted.
+    pub fn set_stop_sequences(&mut self, stop_sequences: PackedStringArray) {
+        self.stop_sequences = stop_sequences
+            .to_vec()
+            .iter()
+            .map(|sequence| sequence.to_string())
+            .collect();
+    }
+
+    #[func]
+    /// Sets the bias added to the logits of tokens in the following prompt and chat calls.
+    /// Keys are either token ids or words. Positive values make a token more likely and negative
+    /// ones less likely. Requires a loaded model as the token ids depend on its tokenizer.
+    ///
+    /// A word only biases its first token, with and without a leading space, so a word written
+    /// as several tokens also makes the other words starting with that token more or less likely.
+    /// Use set_banned_words to keep the model from writing a whole word. Entries that are not a
+    /// number mapped to a token id or word are reported as errors and skipped.
+    pub fn set_logit_bias(&mut self, bias: Dictionary) {
+        let pipeline = self.pipeline.borrow();
+        let Some(pipeline) = pipeline.as_ref() else {
+            godot_error!("no model loaded");
+            return;
+        };
+        let mut logit_bias = HashMap::new();
+        for (key, value) in bias.iter_shared() {
+            let Ok(value) = value.try_to::<f64>() else {
+                godot_error!("the logit bias of {key} is not a number");
+                continue;
+            };
+            if let Ok(token) = key.try_to::<i64>() {
+                logit_bias.insert(token as u32, value as f32);
+                continue;
+            }
+            let Ok(word) = key.try_to::<String>() else {
+                godot_error!("{key} is neither a token id nor a word");
+                continue;
+            };
+            match pipeline.word_tokens(&word) {
+                Ok(spellings) => {
+                    for tokens in spellings {
+                        logit_bias.insert(tokens[0], value as f32);
+                    }
+                }
+                Err(err) => godot_error!("cannot tokenize {word}: {err}"),
+            }
+        }
+        self.logit_bias = logit_bias;
+    }
+
+    #[func]
+    /// Sets words and phrases the model may not write in the following prompt and chat calls.
+    pub fn set_banned_words(&mut self, words: PackedStringArray) {
+        self.banned_words = words.to_vec().iter().map(|word| word.to_string()).collect();
+    }
+
+    #[func]
+    /// Restricts the output of the following prompt and chat calls to text matching a GBNF
+    /// grammar starting at its `root` rule. An empty string removes the grammar. A grammar that
+    /// cannot be parsed is reported as an error and the previous one is kept.
+    pub fn set_grammar(&mut self, grammar: String) {
+        if grammar.trim().is_empty() {
+            self.grammar = None;
+            return;
+        }
+        match Grammar::parse(&grammar) {
+            Ok(grammar) => self.grammar = Some(Arc::new(grammar)),
+            Err(err) => godot_error!("invalid grammar: {err}"),
+        }
+    }
+
+    #[func]
+    /// Restricts the output of the following prompt and chat calls to JSON documents valid
+    /// against a JSON Schema, given as JSON text. An empty string removes the constraint. A schema
+    /// that cannot be turned into a grammar is reported as an error and the previous constraint
+    /// is kept.
+    pub fn set_json_schema(&mut self, schema: String) {
+        if schema.trim().is_empty() {
+            self.grammar = None;
+            return;
+        }
+        let grammar = serde_json::from_str::<serde_json::Value>(&schema)
+            .map_err(E::from)
+            .and_then(|schema| Grammar::from_json_schema(&schema));
+        match grammar {
+            Ok(grammar) => self.grammar = Some(Arc::new(grammar)),
+            Err(err) => godot_error!("invalid JSON Schema: {err}"),
+        }
+    }
+
+    #[func]
+    /// Makes the "token" signal of the following prompt and chat calls report how likely each
+    /// generated token was along with the `top_n` most likely alternatives. A negative `top_n`
+    /// turns this off again.
+    pub fn set_logprobs(&mut self, top_n: i64) {
+        self.logprobs = usize::try_from(top_n).ok();
+    }
+
+    #[func]
+    /// Sets what the following prompt and chat calls do when the conversation no longer fits in
+    /// the model's context window: "error" ends the reply, "truncate" forgets the oldest chat
+    /// messages but keeps the one passed to start_chat, and "shift" forgets half of everything
+    /// after it. Other strategies are reported as errors and the current one is kept.
+    pub fn set_context_overflow(&mut self, strategy: String) {
+        self.context_overflow = match strategy.as_str() {
+            "error" => ContextOverflow::Error,
+            "truncate" => ContextOverflow::TruncateOldest,
+            "shift" => ContextOverflow::Shift,
+            _ => {
+                godot_error!("unknown context overflow strategy {strategy}");
+                return;
+            }
+        };
+    }
+
+    #[func]
+    /// Ends the following prompt and chat calls once they ran for `seconds`. Zero or less removes
+    /// the limit.
+    pub fn set_time_limit(&mut self, seconds: f64) {
+        self.time_limit = Some(seconds)
+            .filter(|seconds| *seconds > 0.0)
+            .map(Duration::from_secs_f64);
+    }
+
+    #[func]
+    /// Returns a token that stops the running prompt or chat call of this generator before its
+    /// next token. Unlike the generator itself, the token can be used from another thread while
+    /// the generator is busy, e.g. when the player walks away from an NPC.
+    pub fn cancel_token(&self) -> Gd<CancelToken> {
+        Gd::from_object(CancelToken {
+            handle: self.cancel.clone(),
+        })
+    }
+
+    #[func]
+    /// Sets how prompt formats the system and user messages for the loaded model to one of the
+    /// built-in "chatml", "llama2", "llama3", "zephyr" or "alpaca" formats. Models come with the
+    /// template of their tokenizer config, or Zephyr when they have none.
+    pub fn set_chat_format(&mut self, format: String) {
+        let format = ChatFormat::from_name(&format).expect("unknown chat format");
+        let mut pipeline = self.pipeline.borrow_mut();
+        let pipeline = pipeline.as_mut().expect("no model loaded");
+        pipeline.chat_template = Some(format.template());
+    }
+
+    #[func]
+    /// Like set_chat_format with a Jinja chat template as found in a tokenizer_config.json, which
+    /// has to go through `messages`. The template gets the special tokens of the model's
+    /// current template.
+    pub fn set_chat_template(&mut self, template: String) {
+        let mut pipeline = self.pipeline.borrow_mut();
+        let pipeline = pipeline.as_mut().expect("no model loaded");
+        let current = pipeline.chat_template.as_ref();
+        let bos_token = current.map_or("", ChatTemplate::bos_token);
+        let eos_token = current.map_or("", ChatTemplate::eos_token);
+        let template = ChatTemplate::new(template, bos_token, eos_token).unwrap();
+        pipeline.chat_template = Some(template);
+    }
+
     #[func]
     /// Starts text generation against the loaded model.
     /// The generated tokens are accumulated in the tokens vec of the this struct.
@@ -59,9 +534,13 @@ impl TextGenerator {
     /// It can however be called relatively safely from a multi-threaded context.
     ///
     /// This function will emit the "token" signal each time a new token is generated.
-    /// The "token" signal does not consume the token.
+    /// The "token" signal does not consume the token. Its second argument holds a dictionary
+    /// with the "token", "text", "bytes", "logprob" and "top" alternatives of every token behind
+    /// the text when set_logprobs was called, and is empty otherwise. "text" keeps the leading
+    /// space of a token, and "bytes" holds the part of a character a byte token stands for.
     ///
-    /// This function will also emit the "finished" signal when generation has finished.
+    /// This function will also emit the "finished" signal when generation has finished, preceded
+    /// by the "error" signal when the text could not be generated.
     pub fn prompt(
         &mut self,
         system: String,
@@ -70,82 +549,239 @@ impl TextGenerator {
         repeat_penalty: f32,
         repeat_last_n: u64,
     ) {
-        let eos_token_id = self
-            .pipeline
-            .borrow_mut()
-            .as_mut()
-            .unwrap()
-            .tokenizer
-            .token_to_id("</s>")
-            .unwrap();
-        let prompt_template =
-            "<|system|>{system}</s>\n<|user|>{prompt}</s>\n<|assistant|>".to_string();
-
-        let mut tokens = self
-            .pipeline
-            .borrow_mut()
-            .as_mut()
-            .unwrap()
-            .tokenizer
-            .encode(prompt.clone(), true)
-            .map_err(E::msg)
-            .unwrap()
-            .get_ids()
-            .to_vec();
-
-        println!("Starting the inference loop");
-        println!("{prompt:?}");
-        // Inference loop
-        let mut index_pos = 0;
-        for i in 0..sample_len {
-            let (context_size, context_index) = if self
-                .pipeline
-                .borrow_mut()
-                .as_ref()
-                .unwrap()
-                .cache
-                .use_kv_cache
-                && i > 0
-            {
-                (1, index_pos)
-            } else {
-                (tokens.len(), 0)
-            };
+        self.cancel.reset();
+        let config = self.generation_config(sample_len, repeat_penalty, repeat_last_n);
 
-            let (token, ctxt_len) = {
-                self.pipeline
-                    .borrow_mut()
-                    .as_mut()
-                    .unwrap()
-                    .next_token(
-                        &tokens,
-                        repeat_penalty,
-                        repeat_last_n.try_into().unwrap(),
-                        context_size,
-                        context_index,
-                    )
-                    .unwrap()
-            };
+        let mut messages = Vec::new();
+        if !system.is_empty() {
+            messages.push(ChatMessage::system(system));
+        }
+        messages.push(ChatMessage::user(prompt));
 
-            index_pos += ctxt_len;
+        // The pipeline is taken out of the cell so that signals can be emitted while it runs
+        let Some(pipeline) = self.pipeline.take() else {
+            self.emit_finished(Err(E::msg("no model loaded")));
+            return;
+        };
+        let result = pipeline
+            .generate_chat(&messages, config)
+            .and_then(|generation| self.emit_generation(&pipeline, generation));
+        self.pipeline.replace(Some(pipeline));
+        self.emit_finished(result.map(|_| ()));
+    }
 
-            // I think I need to simplify my types...
-            let decoded_token = {
-                self.pipeline
-                    .borrow_mut()
-                    .as_mut()
-                    .unwrap()
-                    .decode(&[token.clone()])
-            };
-            self.base_mut()
-                .emit_signal("token".into(), &[decoded_token.to_variant()]);
-            tokens.push(token);
-            if token == eos_token_id {
-                // If we get an eos token we stop generating
-                break;
+    #[func]
+    /// Like prompt, but continues `text` as it is without formatting it with the chat template.
+    /// This is the way to prompt base models that were never trained to chat, such as the
+    /// llama2.c story models.
+    ///
+    /// Emits the same "token", "error" and "finished" signals as prompt.
+    pub fn complete(
+        &mut self,
+        text: String,
+        sample_len: i32,
+        repeat_penalty: f32,
+        repeat_last_n: u64,
+    ) {
+        self.cancel.reset();
+        let config = self.generation_config(sample_len, repeat_penalty, repeat_last_n);
+
+        let Some(pipeline) = self.pipeline.take() else {
+            self.emit_finished(Err(E::msg("no model loaded")));
+            return;
+        };
+        let result = pipeline
+            .generate(&text, config)
+            .and_then(|generation| self.emit_generation(&pipeline, generation));
+        self.pipeline.replace(Some(pipeline));
+        self.emit_finished(result.map(|_| ()));
+    }
+
+    #[func]
+    /// Continues the conversation held by this generator with a new message, formatted with the
+    /// model's chat template like prompt. Unlike prompt the earlier turns stay in the model's KV
+    /// cache so they are not processed again. Call reset_chat to start a new conversation.
+    ///
+    /// Emits the same "token", "error" and "finished" signals as prompt. The message is only
+    /// added to the conversation when the reply could be generated.
+    pub fn chat(
+        &mut self,
+        message: String,
+        sample_len: i32,
+        repeat_penalty: f32,
+        repeat_last_n: u64,
+    ) {
+        self.cancel.reset();
+        let config = self.generation_config(sample_len, repeat_penalty, repeat_last_n);
+
+        let Some(pipeline) = self.pipeline.take() else {
+            self.emit_finished(Err(E::msg("no model loaded")));
+            return;
+        };
+        let mut messages = self.chat_messages.clone();
+        messages.push(ChatMessage::user(message));
+        let session = match self.session.take() {
+            Some(session) => Ok(session),
+            None => pipeline.new_session(),
+        };
+        let result = session.and_then(|mut session| {
+            // The whole chat is formatted again, the session only runs the tokens it does not hold
+            let reply = pipeline
+                .encode_chat(&messages, true)
+                .and_then(|tokens| session.generate_tokens(&pipeline, &tokens, config))
+                .and_then(|generation| self.emit_generation(&pipeline, generation));
+            self.session = Some(session);
+            reply
+        });
+        self.pipeline.replace(Some(pipeline));
+        let result = result.map(|reply| {
+            messages.push(ChatMessage::assistant(reply));
+            self.chat_messages = messages;
+        });
+        self.emit_finished(result);
+    }
+
+    #[func]
+    /// Starts a new conversation beginning with `prefix` as its system message, e.g. an NPC's
+    /// persona. The prefix is run through the model once and reused by every conversation
+    /// starting with the same prefix, so switching between NPCs does not process their personas
+    /// again. The last 8 prefixes used are kept, see set_prefix_cache_capacity.
+    pub fn start_chat(&mut self, prefix: String) {
+        self.session = None;
+        self.chat_messages = Vec::new();
+        if !prefix.is_empty() {
+            self.chat_messages.push(ChatMessage::system(prefix));
+        }
+        let pipeline = self.pipeline.borrow();
+        let Some(pipeline) = pipeline.as_ref() else {
+            godot_error!("no model loaded");
+            return;
+        };
+        // Without a cached prefix the next chat call runs the system message along with its turn
+        let session = pipeline
+            .encode_chat(&self.chat_messages, false)
+            .and_then(|tokens| self.prefix_cache.session_for_tokens(pipeline, tokens));
+        match session {
+            Ok(session) => self.session = Some(session),
+            Err(err) => godot_error!("{err}"),
+        }
+    }
+
+    #[func]
+    /// Sets how many prefixes start_chat keeps run through the model, each holding memory for its
+    /// KV cache. The least recently used prefixes are dropped first, zero keeps none.
+    pub fn set_prefix_cache_capacity(&mut self, capacity: i64) {
+        self.prefix_cache
+            .set_capacity(usize::try_from(capacity).unwrap_or(0));
+    }
+
+    #[func]
+    /// Forgets the conversation held by this generator.
+    pub fn reset_chat(&mut self) {
+        self.session = None;
+        self.chat_messages.clear();
+    }
+
+    #[func]
+    /// Lets the model call `callable` in conversations started with start_conversation.
+    /// `parameters` is the JSON Schema of the arguments, which are passed to the callable as a
+    /// single Dictionary. Whatever the callable returns is turned into JSON and shown to the
+    /// model. Registering a tool under the name of another replaces it.
+    pub fn register_tool(
+        &mut self,
+        name: String,
+        description: String,
+        parameters: String,
+        callable: Callable,
+    ) {
+        let parameters = serde_json::from_str(&parameters).unwrap();
+        self.tools.retain(|(tool, _)| tool.name != name);
+        self.tools
+            .push((Tool::new(name, description, parameters), callable));
+        self.update_tools();
+    }
+
+    #[func]
+    pub fn unregister_tool(&mut self, name: String) {
+        self.tools.retain(|(tool, _)| tool.name != name);
+        self.update_tools();
+    }
+
+    #[func]
+    /// Starts a conversation with the registered tools, continued with say.
+    /// The history is kept within `token_budget` tokens: the oldest turns are summarized in up
+    /// to `summary_tokens` tokens, or dropped when `summary_tokens` is zero.
+    pub fn start_conversation(&mut self, system: String, token_budget: i64, summary_tokens: i64) {
+        let system = (!system.is_empty()).then_some(system);
+        let overflow = if summary_tokens > 0 {
+            HistoryOverflow::Summarize {
+                max_tokens: summary_tokens as usize,
             }
+        } else {
+            HistoryOverflow::Drop
+        };
+        let conversation = Conversation::new(system, token_budget as usize, overflow);
+        self.conversation = Some(conversation);
+        self.conversation_session = None;
+        self.update_tools();
+    }
+
+    #[func]
+    /// Adds a fact the conversation never forgets, e.g. a quest the player accepted.
+    pub fn pin_fact(&mut self, fact: String) {
+        self.conversation
+            .as_mut()
+            .expect("no conversation started")
+            .pin(fact);
+    }
+
+    #[func]
+    /// Continues the conversation started with start_conversation with a message of the player.
+    /// When the model calls a tool, the tool's callable is run, the "tool_called" signal is
+    /// emitted and the result is given to the model, which then carries on with its reply. The
+    /// tool calls themselves are not part of the text sent with the "token" signal.
+    ///
+    /// Emits the same "token" and "finished" signals as prompt. When the reply cannot be
+    /// generated the "error" signal is emitted before "finished", and the conversation is left as
+    /// it was before the message so that the message can be said again.
+    pub fn say(
+        &mut self,
+        message: String,
+        sample_len: i32,
+        repeat_penalty: f32,
+        repeat_last_n: u64,
+    ) {
+        self.cancel.reset();
+        let mut config = self.generation_config(sample_len, repeat_penalty, repeat_last_n);
+        if !config
+            .stop_sequences
+            .iter()
+            .any(|stop| stop == TOOL_CALL_END)
+        {
+            config.stop_sequences.push(TOOL_CALL_END.to_string());
         }
-        self.base_mut().emit_signal("finished".into(), &[]);
+
+        assert!(self.conversation.is_some(), "no conversation started");
+        let pipeline = self.pipeline.take().expect("no model loaded");
+        let mut conversation = self.conversation.take().unwrap();
+        let session = match self.conversation_session.take() {
+            Some(session) => Ok(session),
+            None => pipeline.new_session(),
+        };
+        let result = session.and_then(|mut session| {
+            // A reply failing partway, after the message was added, leaves no trace of the message
+            let before = conversation.clone();
+            let result = self.converse(&pipeline, &mut conversation, &mut session, message, config);
+            if result.is_err() {
+                conversation = before;
+            }
+            self.conversation_session = Some(session);
+            result
+        });
+        // The model and the conversation are put back before any signal is handled
+        self.conversation = Some(conversation);
+        self.pipeline.replace(Some(pipeline));
+        self.emit_finished(result);
     }
 
     #[func]
@@ -164,6 +800,225 @@ impl TextGenerator {
     }
 }
 
+/// The most replies the model writes for a single message given to say, so that a model calling
+/// tools over and over does not hang the game.
+const MAX_TOOL_ROUNDS: usize = 5;
+
+impl TextGenerator {
+    fn generation_config(
+        &self,
+        sample_len: i32,
+        repeat_penalty: f32,
+        repeat_last_n: u64,
+    ) -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: sample_len.try_into().unwrap_or(0),
+            repeat_penalty,
+            repeat_last_n: repeat_last_n.try_into().unwrap(),
+            sampling: self.sampling.clone(),
+            stop_sequences: self.stop_sequences.clone(),
+            logit_bias: self.logit_bias.clone(),
+            banned_strings: self.banned_words.clone(),
+            grammar: self.grammar.clone(),
+            logprobs: self.logprobs,
+            context_overflow: self.context_overflow,
+            cancel: Some(self.cancel.clone()),
+            deadline: self.time_limit.map(|limit| Instant::now() + limit),
+            cooperative_yield: self.cooperative_yield,
+            ..Default::default()
+        }
+    }
+
+    fn add_lora(&mut self, name: String, source: &ModelSource) {
+        let pipeline = self.pipeline.borrow();
+        let pipeline = pipeline.as_ref().expect("no model loaded");
+        let adapter = pipeline.load_lora(source).unwrap();
+        self.loras.insert(name, adapter);
+    }
+
+    /// Emits the "token" signal for every chunk of text produced by `generation` and returns the
+    /// whole text.
+    fn emit_generation(
+        &mut self,
+        pipeline: &TextGeneration,
+        mut generation: Generation,
+    ) -> Result<String, E> {
+        let mut text = String::new();
+        while let Some(chunk) = generation.next_chunk() {
+            let chunk = chunk?;
+            text.push_str(&chunk.text);
+            let logprobs: Array<Dictionary> = chunk
+                .logprobs
+                .iter()
+                .map(|logprobs| logprobs_dictionary(pipeline, logprobs))
+                .collect();
+            self.base_mut().emit_signal(
+                "token".into(),
+                &[chunk.text.to_variant(), logprobs.to_variant()],
+            );
+        }
+        self.last_summary = generation.summary();
+        Ok(text)
+    }
+
+    /// Emits the "finished" signal, preceded by the "error" signal when `result` is an error.
+    fn emit_finished(&mut self, result: Result<(), E>) {
+        if let Err(err) = result {
+            self.base_mut()
+                .emit_signal("error".into(), &[err.to_string().to_variant()]);
+        }
+        self.base_mut().emit_signal("finished".into(), &[]);
+    }
+
+    /// Emits the "token" signal for the text of a reply in a conversation and returns the whole
+    /// reply. Text that may be the start of a tool call is held back until it turns out not to
+    /// be, along with the logprobs of its tokens.
+    fn emit_reply(
+        &mut self,
+        pipeline: &TextGeneration,
+        generation: &mut Generation,
+    ) -> Result<String, E> {
+        let mut reply = String::new();
+        let mut shown = 0;
+        let mut logprobs = Array::new();
+        loop {
+            let chunk = generation.next_chunk().transpose()?;
+            let visible = match &chunk {
+                Some(chunk) => {
+                    reply.push_str(&chunk.text);
+                    for chunk_logprobs in chunk.logprobs.iter() {
+                        logprobs.push(logprobs_dictionary(pipeline, chunk_logprobs));
+                    }
+                    tools::visible_len(&reply)
+                }
+                None => reply.find(TOOL_CALL_START).unwrap_or(reply.len()),
+            };
+            if visible > shown {
+                let text = &reply[shown..visible];
+                let logprobs = std::mem::take(&mut logprobs);
+                self.base_mut()
+                    .emit_signal("token".into(), &[text.to_variant(), logprobs.to_variant()]);
+                shown = visible;
+            }
+            if chunk.is_none() {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Replies to `message` in `conversation`, running the tools the model calls.
+    fn converse(
+        &mut self,
+        pipeline: &TextGeneration,
+        conversation: &mut Conversation,
+        session: &mut Session,
+        message: String,
+        config: GenerationConfig,
+    ) -> Result<(), E> {
+        let mut generation = conversation.reply(pipeline, session, message, config.clone())?;
+        for round in 1..=MAX_TOOL_ROUNDS {
+            let mut reply = self.emit_reply(pipeline, &mut generation)?;
+            self.last_summary = generation.summary();
+            drop(generation);
+            if reply.contains(TOOL_CALL_START) && !reply.contains(TOOL_CALL_END) {
+                reply.push_str(TOOL_CALL_END);
+            }
+            let call = conversation.push_reply(reply, |call| self.call_tool(call));
+            let cancelled = self.cancel.is_cancelled();
+            if call.is_none() || cancelled || round == MAX_TOOL_ROUNDS {
+                break;
+            }
+            generation = conversation.generate(pipeline, session, config.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Runs the callable registered for the tool `call` is for.
+    fn call_tool(&mut self, call: &ToolCall) -> Result<serde_json::Value, E> {
+        let Some((_, callable)) = self.tools.iter().find(|(tool, _)| tool.name == call.name) else {
+            anyhow::bail!("there is no tool named {}", call.name);
+        };
+        let callable = callable.clone();
+        let arguments = Json::parse_string(call.arguments.to_string().into());
+        let result = callable.callv(varray![arguments.clone()]);
+        self.base_mut().emit_signal(
+            "tool_called".into(),
+            &[call.name.to_variant(), arguments, result.clone()],
+        );
+        Ok(serde_json::from_str(&Json::stringify(result).to_string())?)
+    }
+
+    /// Gives the conversation the tools registered so far.
+    fn update_tools(&mut self) {
+        let tools = self.tools.iter().map(|(tool, _)| tool.clone()).collect();
+        if let Some(conversation) = self.conversation.as_mut() {
+            conversation.set_tools(tools);
+        }
+    }
+}
+
+/// Stops the generation of the TextGenerator it was taken from, see
+/// `TextGenerator::cancel_token`.
+#[derive(GodotClass)]
+#[class(base=RefCounted)]
+pub struct CancelToken {
+    handle: CancelHandle,
+}
+
+#[godot_api]
+impl CancelToken {
+    #[func]
+    /// Stops the running prompt or chat call. Calls started afterwards are not affected.
+    pub fn cancel(&self) {
+        self.handle.cancel();
+    }
+
+    #[func]
+    pub fn is_cancelled(&self) -> bool {
+        self.handle.is_cancelled()
+    }
+}
+
+/// The memory estimate of `which_model` as returned by `TextGenerator::estimate_memory`.
+fn memory_dictionary(source: &ModelSource, which_model: &str, context_length: i64) -> Dictionary {
+    let context_length = usize::try_from(context_length).ok().filter(|n| *n > 0);
+    // Models are loaded as f16 unless their weights are quantized
+    let estimate =
+        MemoryEstimate::from_source(source, which_model, DType::F16, context_length).unwrap();
+    let mut dictionary = Dictionary::new();
+    dictionary.insert("weights", estimate.weights as i64);
+    dictionary.insert("kv_cache", estimate.kv_cache as i64);
+    dictionary.insert("total", estimate.total() as i64);
+    dictionary.insert("context_length", estimate.context_length as i64);
+    dictionary
+}
+
+/// A dictionary describing a generated token for the "token" signal.
+fn logprobs_dictionary(pipeline: &TextGeneration, logprobs: &TokenLogprobs) -> Dictionary {
+    let token_dictionary = |token: u32, logprob: f32| {
+        let bytes = pipeline.token_bytes(token);
+        // Tokens holding part of a character are shown as written in the vocabulary
+        let text = String::from_utf8(bytes.clone())
+            .ok()
+            .or_else(|| pipeline.tokenizer.id_to_token(token))
+            .unwrap_or_default();
+        let mut dictionary = Dictionary::new();
+        dictionary.insert("token", token as i64);
+        dictionary.insert("text", text);
+        dictionary.insert("bytes", PackedByteArray::from(bytes.as_slice()));
+        dictionary.insert("logprob", logprob as f64);
+        dictionary
+    };
+    let mut dictionary = token_dictionary(logprobs.token, logprobs.logprob);
+    let top: Array<Dictionary> = logprobs
+        .top
+        .iter()
+        .map(|(token, logprob)| token_dictionary(*token, *logprob))
+        .collect();
+    dictionary.insert("top", top);
+    dictionary
+}
+
 pub struct Jovia {}
 
 impl Jovia {
@@ -0,0 +1,51 @@
+use anyhow::{Error as E, Result};
+use std::collections::HashMap;
+
+use crate::generation::GenerationConfig;
+use crate::text_generation::TextGeneration;
+
+/// The logit bias and banned tokens of a generation, resolved to token ids.
+#[derive(Debug, Clone, Default)]
+pub struct LogitBias {
+    bias: HashMap<u32, f32>,
+    /// Token sequences that may not be generated. The last token of a sequence is banned whenever
+    /// the tokens before it were just generated, so single tokens are always banned.
+    banned: Vec<Vec<u32>>,
+}
+
+impl LogitBias {
+    pub fn new(bias: HashMap<u32, f32>, banned: Vec<Vec<u32>>) -> Self {
+        let banned = banned.into_iter().filter(|seq| !seq.is_empty()).collect();
+        Self { bias, banned }
+    }
+
+    /// Resolves the bias and banned tokens and strings of `config` with the pipeline's tokenizer.
+    pub fn from_config(pipeline: &TextGeneration, config: &GenerationConfig) -> Result<Self, E> {
+        let mut banned: Vec<Vec<u32>> = config.banned_tokens.iter().map(|t| vec![*t]).collect();
+        for text in &config.banned_strings {
+            banned.extend(pipeline.word_tokens(text)?);
+        }
+        Ok(Self::new(config.logit_bias.clone(), banned))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bias.is_empty() && self.banned.is_empty()
+    }
+
+    /// Adds the bias to `logits` and masks the banned tokens that could follow `tokens`.
+    pub fn apply(&self, logits: &mut [f32], tokens: &[u32]) {
+        for (&token, &bias) in &self.bias {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit += bias;
+            }
+        }
+        for seq in &self.banned {
+            let (last, prefix) = seq.split_last().expect("banned sequences are not empty");
+            if tokens.ends_with(prefix) {
+                if let Some(logit) = logits.get_mut(*last as usize) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+}
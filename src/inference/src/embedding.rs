@@ -2,10 +2,11 @@ use anyhow::{Error as E, Result};
 use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config, HiddenAct, DTYPE};
-use hf_hub::api::sync::Api;
-use hf_hub::{Repo, RepoType};
+use std::path::PathBuf;
 use tokenizers::{PaddingParams, Tokenizer};
 
+use crate::source::{ModelFiles, ModelSource};
+
 pub struct EmbeddingModel {
     pub tracing: bool,
     pub model_id: Option<String>,
@@ -23,7 +24,6 @@ impl EmbeddingModel {
         model_id: Option<String>,
         revision: Option<String>,
     ) -> Result<Self, E> {
-        let device = Device::Cpu;
         let mut default_model = "sentence-transformers/all-MiniLM-L6-v2".to_string();
         let mut default_revision = "refs/pr/21".to_string();
 
@@ -35,30 +35,41 @@ impl EmbeddingModel {
             default_revision = revision;
         }
 
-        let repo = Repo::with_revision(
-            default_model.clone(),
-            RepoType::Model,
-            default_revision.clone(),
-        );
-        let (config_filename, tokenizer_filename, weights_filename) = {
-            let api = Api::new()?;
-            let api = api.repo(repo);
-            let config = api.get("config.json")?;
-            let tokenizer = api.get("tokenizer.json")?;
-            let weights = api.get("model.safetensors")?;
-            (config, tokenizer, weights)
-        };
-        let config = std::fs::read_to_string(config_filename)?;
-        let mut config: Config = serde_json::from_str(&config)?;
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-        let vb =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DTYPE, &device)? };
+        let source = ModelSource::hub(default_model, Some(default_revision.clone()))?;
+        let mut model = Self::from_source(&source, tracing)?;
+        model.revision = Some(default_revision);
+        Ok(model)
+    }
+
+    /// Loads the model from a local directory laid out like a hub repository, without any
+    /// network access.
+    pub fn from_local<P: Into<PathBuf>>(model_dir: P, tracing: bool) -> Result<Self, E> {
+        let source = ModelSource::local(model_dir)?;
+        Self::from_source(&source, tracing)
+    }
+
+    pub fn from_source(source: &ModelSource, tracing: bool) -> Result<Self, E> {
+        let files = ModelFiles::from_source(source, "model.safetensors")?;
+        Self::from_files(Some(source.id()), files, tracing)
+    }
+
+    /// Loads the model from explicit file paths, without any network access.
+    pub fn from_files(
+        model_id: Option<String>,
+        files: ModelFiles,
+        tracing: bool,
+    ) -> Result<Self, E> {
+        let device = Device::Cpu;
+        let config = std::fs::read_to_string(files.config()?)?;
+        let config: Config = serde_json::from_str(&config)?;
+        let tokenizer = Tokenizer::from_file(&files.tokenizer).map_err(E::msg)?;
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files.weights, DTYPE, &device)? };
         let model = BertModel::load(vb, &config)?;
 
         Ok(EmbeddingModel {
             tracing,
-            model_id: Some(default_model),
-            revision: Some(default_revision),
+            model_id,
+            revision: None,
             model,
             tokenizer,
             device,
@@ -0,0 +1,610 @@
+use anyhow::{Error as E, Result};
+use std::collections::{HashMap, VecDeque};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bias::LogitBias;
+use crate::grammar::{Grammar, GrammarState};
+use crate::sampling::{Sampler, SamplingConfig, TokenLogprobs};
+use crate::session::{ContextOverflow, Session};
+use crate::text_generation::{TextGeneration, TokenOutputStream};
+
+/// Parameters for a single call to [`TextGeneration::generate`].
+#[derive(Debug, Clone)]
+pub struct GenerationConfig {
+    /// The maximum number of tokens to generate.
+    pub max_new_tokens: usize,
+    pub repeat_penalty: f32,
+    /// The number of trailing tokens the repeat penalty is applied to.
+    pub repeat_last_n: usize,
+    /// The sampling for this generation, `None` uses the pipeline's default.
+    pub sampling: Option<SamplingConfig>,
+    /// Strings that end the generation when they appear in the decoded text. The stop sequence
+    /// itself is not part of the output.
+    pub stop_sequences: Vec<String>,
+    /// Added to the logits of the given token ids before sampling.
+    pub logit_bias: HashMap<u32, f32>,
+    /// Token ids that are never generated.
+    pub banned_tokens: Vec<u32>,
+    /// Words or phrases that are never generated, tokenized with the pipeline's tokenizer.
+    pub banned_strings: Vec<String>,
+    /// Restricts the output to text matching the grammar, the generation ends once the grammar
+    /// cannot be continued.
+    pub grammar: Option<Arc<Grammar>>,
+    /// The number of tokens the draft model proposes per step when the pipeline has one, zero
+    /// disables speculative decoding.
+    pub draft_tokens: usize,
+    /// Records the log-probability of every generated token along with this many of the most
+    /// likely alternatives, see [`Generation::next_chunk`]. `None` skips computing them.
+    pub logprobs: Option<usize>,
+    /// What to do when the conversation fills the model's context window.
+    pub context_overflow: ContextOverflow,
+    /// Stops the generation with [`StopReason::Cancelled`] once cancelled.
+    pub cancel: Option<CancelHandle>,
+    /// Stops the generation with [`StopReason::DeadlineExceeded`] once this time has passed.
+    pub deadline: Option<Instant>,
+    /// Pauses the generation between forward passes to leave the CPU to other threads.
+    pub cooperative_yield: Option<CooperativeYield>,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            max_new_tokens: 256,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            sampling: None,
+            stop_sequences: Vec::new(),
+            logit_bias: HashMap::new(),
+            banned_tokens: Vec::new(),
+            banned_strings: Vec::new(),
+            grammar: None,
+            draft_tokens: 4,
+            logprobs: None,
+            context_overflow: ContextOverflow::Error,
+            cancel: None,
+            deadline: None,
+            cooperative_yield: None,
+        }
+    }
+}
+
+/// Makes a generation sleep for `pause` whenever it has been computing for `every` since the last
+/// pause, so that a game thread sharing the cores gets to run. The check happens between forward
+/// passes, so a long prompt is still processed in one go.
+///
+/// The generation sleeps on the thread driving it, so it should have a thread of its own rather
+/// than run on the thread rendering the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CooperativeYield {
+    pub every: Duration,
+    /// A zero pause only yields the rest of the thread's time slice.
+    pub pause: Duration,
+}
+
+impl CooperativeYield {
+    pub fn new(every: Duration, pause: Duration) -> Self {
+        Self { every, pause }
+    }
+}
+
+/// Stops generations from another thread, see [`GenerationConfig::cancel`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the generations using this handle stop before their next token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clears the cancellation so that the handle can be used for another generation.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Why a generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model produced one of the pipeline's stop tokens or the grammar was completed.
+    EndOfSequence,
+    /// The decoded text contained one of the configured stop sequences.
+    StopSequence,
+    /// `max_new_tokens` tokens were generated.
+    MaxTokens,
+    /// The context window of the model was full and the configured [`ContextOverflow`] could not
+    /// make room.
+    ContextFull,
+    /// The [`CancelHandle`] of the generation was cancelled.
+    Cancelled,
+    /// The deadline of the generation passed.
+    DeadlineExceeded,
+    /// The model or tokenizer returned an error, which was yielded by the stream.
+    Error,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::EndOfSequence => "end_of_sequence",
+            Self::StopSequence => "stop_sequence",
+            Self::MaxTokens => "max_tokens",
+            Self::ContextFull => "context_full",
+            Self::Cancelled => "cancelled",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Summary and timings of a finished generation.
+#[derive(Debug, Clone)]
+pub struct GenerationSummary {
+    pub stop_reason: StopReason,
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    /// The time from the start of the generation until the first token was sampled, `None` when
+    /// no token was generated.
+    pub time_to_first_token: Option<Duration>,
+    /// The time spent feeding the prompt to the model and sampling the first token.
+    pub prefill_time: Duration,
+    /// The time spent generating the tokens after the first forward pass.
+    pub decode_time: Duration,
+    /// The number of tokens generated in `decode_time`.
+    pub decode_tokens: usize,
+    /// How the draft model did, `None` when speculative decoding was not used.
+    pub speculative: Option<SpeculativeStats>,
+}
+
+impl GenerationSummary {
+    /// The generation speed after the prompt was processed, zero when no time was measured.
+    pub fn decode_tokens_per_second(&self) -> f64 {
+        let seconds = self.decode_time.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.decode_tokens as f64 / seconds
+    }
+
+    /// The number of prompt tokens processed per second.
+    pub fn prefill_tokens_per_second(&self) -> f64 {
+        let seconds = self.prefill_time.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.prompt_tokens as f64 / seconds
+    }
+
+    /// The summary as a JSON object, with durations in seconds.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "stop_reason": self.stop_reason.as_str(),
+            "prompt_tokens": self.prompt_tokens,
+            "generated_tokens": self.generated_tokens,
+            "time_to_first_token": self.time_to_first_token.map(|time| time.as_secs_f64()),
+            "prefill_time": self.prefill_time.as_secs_f64(),
+            "decode_time": self.decode_time.as_secs_f64(),
+            "decode_tokens_per_second": self.decode_tokens_per_second(),
+            "draft_acceptance_rate": self.speculative.map(|stats| stats.acceptance_rate()),
+        })
+    }
+}
+
+/// Draft token counts of a speculative generation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpeculativeStats {
+    /// The number of tokens proposed by the draft model.
+    pub drafted_tokens: usize,
+    /// The number of proposed tokens that the model agreed with.
+    pub accepted_tokens: usize,
+}
+
+impl SpeculativeStats {
+    /// The share of draft tokens that were accepted.
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted_tokens == 0 {
+            return 0.0;
+        }
+        self.accepted_tokens as f64 / self.drafted_tokens as f64
+    }
+}
+
+/// A piece of generated text with the log-probabilities of the tokens sampled for it.
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub text: String,
+    /// Empty unless [`GenerationConfig::logprobs`] is set.
+    pub logprobs: Vec<TokenLogprobs>,
+}
+
+/// The session a generation runs in, either borrowed from the caller or owned for one-shot
+/// generations.
+pub(crate) enum SessionSlot<'a> {
+    Owned(Session),
+    Borrowed(&'a mut Session),
+}
+
+impl<'a> From<&'a mut Session> for SessionSlot<'a> {
+    fn from(session: &'a mut Session) -> Self {
+        Self::Borrowed(session)
+    }
+}
+
+impl Deref for SessionSlot<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        match self {
+            Self::Owned(session) => session,
+            Self::Borrowed(session) => session,
+        }
+    }
+}
+
+impl DerefMut for SessionSlot<'_> {
+    fn deref_mut(&mut self) -> &mut Session {
+        match self {
+            Self::Owned(session) => session,
+            Self::Borrowed(session) => session,
+        }
+    }
+}
+
+/// A running generation, iterating over the decoded text as it is produced.
+///
+/// The summary becomes available through [`Generation::summary`] once the iterator is exhausted.
+pub struct Generation<'a> {
+    pipeline: &'a TextGeneration,
+    session: SessionSlot<'a>,
+    config: GenerationConfig,
+    sampler: Sampler,
+    bias: LogitBias,
+    grammar: Option<GrammarState>,
+    prompt_tokens: usize,
+    generated_tokens: usize,
+    /// Sampled tokens that were not turned into text yet.
+    queue: VecDeque<(u32, Option<TokenLogprobs>)>,
+    /// The log-probabilities of the tokens turned into text since the last chunk.
+    logprobs: Vec<TokenLogprobs>,
+    speculative: SpeculativeStats,
+    started: Instant,
+    /// When the first and last forward passes ended and how many tokens the first one yielded.
+    first_tokens: Option<(Instant, usize)>,
+    last_tokens_at: Option<Instant>,
+    prefill_time: Duration,
+    /// When the generation last paused for [`GenerationConfig::cooperative_yield`] and for how
+    /// long it has paused since the first forward pass, which is left out of the decode time.
+    last_yield: Instant,
+    paused: Duration,
+    stream: TokenOutputStream,
+    stop_sequences: StopSequences,
+    stop_reason: Option<StopReason>,
+}
+
+impl<'a> Generation<'a> {
+    /// Starts a generation in `session` whose last `prompt_tokens` tokens are the prompt.
+    pub(crate) fn new(
+        pipeline: &'a TextGeneration,
+        session: SessionSlot<'a>,
+        prompt_tokens: usize,
+        config: GenerationConfig,
+    ) -> Result<Self, E> {
+        let started = Instant::now();
+        let bias = LogitBias::from_config(pipeline, &config)?;
+        let grammar = config.grammar.clone().map(GrammarState::new);
+        // The text is decoded after the last token of the prompt so that it keeps its leading space
+        let tokens = session.tokens();
+        let context = &tokens[tokens.len().saturating_sub(1)..];
+        let stream = TokenOutputStream::with_context(pipeline.tokenizer.clone(), context);
+        let sampling = config
+            .sampling
+            .clone()
+            .unwrap_or_else(|| pipeline.sampling.clone());
+        let stop_sequences = StopSequences::new(config.stop_sequences.clone());
+        Ok(Self {
+            pipeline,
+            session,
+            config,
+            sampler: Sampler::new(sampling),
+            bias,
+            grammar,
+            prompt_tokens,
+            generated_tokens: 0,
+            queue: VecDeque::new(),
+            logprobs: Vec::new(),
+            speculative: SpeculativeStats::default(),
+            started,
+            first_tokens: None,
+            last_tokens_at: None,
+            prefill_time: Duration::ZERO,
+            last_yield: started,
+            paused: Duration::ZERO,
+            stream,
+            stop_sequences,
+            stop_reason: None,
+        })
+    }
+
+    /// The session the generation runs in.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// The prompt tokens followed by every token generated so far, without the ones dropped to
+    /// make room in the context window.
+    pub fn tokens(&self) -> &[u32] {
+        let tokens = self.session.tokens();
+        let generation_tokens = self.prompt_tokens + self.generated_tokens;
+        &tokens[tokens.len().saturating_sub(generation_tokens)..]
+    }
+
+    pub fn generated_tokens(&self) -> usize {
+        self.generated_tokens
+    }
+
+    /// The summary of the generation, `None` until it has finished.
+    pub fn summary(&self) -> Option<GenerationSummary> {
+        let (decode_time, decode_tokens) = match (self.first_tokens, self.last_tokens_at) {
+            (Some((first_at, first_tokens)), Some(last_at)) => {
+                let decode_time = (last_at - first_at).saturating_sub(self.paused);
+                (decode_time, self.generated_tokens - first_tokens)
+            }
+            _ => (Duration::ZERO, 0),
+        };
+        self.stop_reason.map(|stop_reason| GenerationSummary {
+            stop_reason,
+            prompt_tokens: self.prompt_tokens,
+            generated_tokens: self.generated_tokens(),
+            time_to_first_token: self.first_tokens.map(|(at, _)| at - self.started),
+            prefill_time: self.prefill_time,
+            decode_time,
+            decode_tokens,
+            speculative: Some(self.speculative).filter(|stats| stats.drafted_tokens > 0),
+        })
+    }
+
+    /// Returns the next piece of text along with the log-probabilities of its tokens, `None` once
+    /// the generation has finished. Iterating over the generation yields the same text without
+    /// the log-probabilities.
+    pub fn next_chunk(&mut self) -> Option<Result<Chunk, E>> {
+        if self.stop_reason.is_some() {
+            return None;
+        }
+        match self.step() {
+            Ok(Some(text)) => Some(Ok(Chunk {
+                text,
+                logprobs: std::mem::take(&mut self.logprobs),
+            })),
+            Ok(None) => None,
+            Err(err) => {
+                self.stop_reason = Some(StopReason::Error);
+                Some(Err(err))
+            }
+        }
+    }
+
+    /// Runs the generation to the end, returning the full generated text and its summary.
+    pub fn complete(mut self) -> Result<(String, GenerationSummary), E> {
+        let mut text = String::new();
+        for chunk in self.by_ref() {
+            text.push_str(&chunk?);
+        }
+        let summary = self.summary().expect("generation finished");
+        Ok((text, summary))
+    }
+
+    /// Stops the generation, returning the text that was still held back.
+    fn finish(&mut self, stop_reason: StopReason) -> Result<Option<String>, E> {
+        self.stop_reason = Some(stop_reason);
+        let rest = self.stream.decode_rest()?.unwrap_or_default();
+        let (mut text, stopped) = self.stop_sequences.push(&rest);
+        if stopped {
+            self.stop_reason = Some(StopReason::StopSequence);
+        } else {
+            text.push_str(&self.stop_sequences.flush());
+        }
+        Ok(Some(text).filter(|text| !text.is_empty()))
+    }
+
+    /// Whether the generation was cancelled or ran out of time.
+    fn interruption(&self) -> Option<StopReason> {
+        if matches!(&self.config.cancel, Some(cancel) if cancel.is_cancelled()) {
+            return Some(StopReason::Cancelled);
+        }
+        if self.config.deadline.is_some_and(|d| Instant::now() >= d) {
+            return Some(StopReason::DeadlineExceeded);
+        }
+        None
+    }
+
+    /// Pauses the thread if the generation has been running for longer than the configured
+    /// [`CooperativeYield::every`].
+    fn cooperate(&mut self) {
+        let Some(cooperative_yield) = self.config.cooperative_yield else {
+            return;
+        };
+        if self.last_yield.elapsed() < cooperative_yield.every {
+            return;
+        }
+        let paused_at = Instant::now();
+        if cooperative_yield.pause.is_zero() {
+            std::thread::yield_now();
+        } else {
+            std::thread::sleep(cooperative_yield.pause);
+        }
+        self.last_yield = Instant::now();
+        if self.first_tokens.is_some() {
+            self.paused += self.last_yield - paused_at;
+        }
+    }
+
+    /// Samples tokens until there is some text to return or the generation stops.
+    fn step(&mut self) -> Result<Option<String>, E> {
+        loop {
+            // Checked for every token as speculative decoding queues several at once
+            if let Some(stop_reason) = self.interruption() {
+                self.discard_queue()?;
+                return self.finish(stop_reason);
+            }
+            let Some((token, logprobs)) = self.queue.pop_front() else {
+                if self.generated_tokens() >= self.config.max_new_tokens {
+                    return self.finish(StopReason::MaxTokens);
+                }
+                if self.grammar.as_ref().is_some_and(|g| g.is_finished()) {
+                    return self.finish(StopReason::EndOfSequence);
+                }
+                if self.session.is_full()
+                    && !self.session.make_room(self.config.context_overflow)?
+                {
+                    return self.finish(StopReason::ContextFull);
+                }
+                self.cooperate();
+                let sampling_started = Instant::now();
+                self.sample_tokens()?;
+                let now = Instant::now();
+                if self.first_tokens.is_none() {
+                    self.first_tokens = Some((now, self.generated_tokens));
+                    self.prefill_time = now - sampling_started;
+                }
+                self.last_tokens_at = Some(now);
+                continue;
+            };
+
+            if self.pipeline.stop_tokens.contains(&token) {
+                return self.finish(StopReason::EndOfSequence);
+            }
+            self.logprobs.extend(logprobs);
+            if let Some(text) = self.stream.next_token(token)? {
+                let (text, stopped) = self.stop_sequences.push(&text);
+                if stopped {
+                    self.stop_reason = Some(StopReason::StopSequence);
+                }
+                if stopped || !text.is_empty() {
+                    return Ok(Some(text).filter(|text| !text.is_empty()));
+                }
+            }
+        }
+    }
+
+    /// Drops the queued tokens, which were never returned, from the session.
+    fn discard_queue(&mut self) -> Result<(), E> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+        let len = self.session.tokens().len() - self.queue.len();
+        self.generated_tokens -= self.queue.len();
+        self.queue.clear();
+        self.session.truncate(len)
+    }
+
+    /// Samples the next token, or several when the session's draft model is used, into `queue`.
+    fn sample_tokens(&mut self) -> Result<(), E> {
+        // Leave room for the token sampled after the last accepted draft token
+        let remaining = self.config.max_new_tokens - self.generated_tokens;
+        let draft_tokens = self.config.draft_tokens.min(remaining - 1);
+        if draft_tokens > 0 && self.session.can_speculate(draft_tokens) {
+            let (tokens, accepted) = self.pipeline.next_tokens_speculative(
+                &mut self.session,
+                &mut self.sampler,
+                &self.bias,
+                self.grammar.as_mut(),
+                self.config.repeat_penalty,
+                self.config.repeat_last_n,
+                self.config.logprobs,
+                draft_tokens,
+            )?;
+            self.speculative.drafted_tokens += draft_tokens;
+            self.speculative.accepted_tokens += accepted;
+            self.generated_tokens += tokens.len();
+            self.queue.extend(tokens);
+        } else {
+            let (token, _, logprobs) = self.pipeline.next_token(
+                &mut self.session,
+                &mut self.sampler,
+                &self.bias,
+                self.grammar.as_mut(),
+                self.config.repeat_penalty,
+                self.config.repeat_last_n,
+                self.config.logprobs,
+            )?;
+            self.generated_tokens += 1;
+            self.queue.push_back((token, logprobs));
+        }
+        Ok(())
+    }
+}
+
+/// Matches stop sequences on streamed text, holding back the end of the text for as long as it
+/// could still be the start of a stop sequence.
+pub(crate) struct StopSequences {
+    sequences: Vec<String>,
+    pending: String,
+}
+
+impl StopSequences {
+    pub(crate) fn new(mut sequences: Vec<String>) -> Self {
+        sequences.retain(|sequence| !sequence.is_empty());
+        Self {
+            sequences,
+            pending: String::new(),
+        }
+    }
+
+    /// Adds decoded `text`, returning the text that is safe to emit and whether a stop sequence
+    /// was found. Everything from the stop sequence on is dropped.
+    pub(crate) fn push(&mut self, text: &str) -> (String, bool) {
+        self.pending.push_str(text);
+        let stop = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| self.pending.find(sequence.as_str()))
+            .min();
+        if let Some(stop) = stop {
+            self.pending.truncate(stop);
+            return (std::mem::take(&mut self.pending), true);
+        }
+        let held = self
+            .sequences
+            .iter()
+            .map(|sequence| partial_match(&self.pending, sequence))
+            .max()
+            .unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        (std::mem::replace(&mut self.pending, rest), false)
+    }
+
+    /// Returns the text held back, once no more text will follow.
+    pub(crate) fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// The length of the longest suffix of `text` that is a proper prefix of `sequence`.
+pub(crate) fn partial_match(text: &str, sequence: &str) -> usize {
+    sequence
+        .char_indices()
+        .skip(1)
+        .map(|(i, _)| i)
+        .filter(|&i| text.ends_with(&sequence[..i]))
+        .max()
+        .unwrap_or(0)
+}
+
+impl Iterator for Generation<'_> {
+    type Item = Result<String, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().map(|chunk| chunk.map(|chunk| chunk.text))
+    }
+}
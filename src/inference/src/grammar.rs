@@ -0,0 +1,647 @@
+use anyhow::{Error as E, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+/// Rule expansions deeper than this are dropped, bounding the stacks of deeply nested text.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    /// A single character in (or, when negated, outside of) the inclusive ranges.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Chars { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+            Self::Rule(_) => false,
+        }
+    }
+}
+
+/// A context free grammar parsed from a GBNF-like text format, as used by llama.cpp:
+///
+/// ```text
+/// root   ::= greeting " " name
+/// greeting ::= "Hello" | "Hi"
+/// name   ::= [A-Z] [a-z]* ("-" [A-Z] [a-z]*)?
+/// ```
+///
+/// Rules are made of string literals, character classes (`[a-z]`, `[^"]`), `.` for any
+/// character, references to other rules and parenthesized groups, optionally followed by `*`, `+`
+/// or `?`. `#` starts a comment. Generation starts at the `root` rule. Left recursive rules, such
+/// as `list ::= list "," item | item`, are refused and have to be written with a repetition
+/// instead: `list ::= item ("," item)*`.
+#[derive(Debug)]
+pub struct Grammar {
+    /// The alternatives of every rule, each a sequence of elements.
+    rules: Vec<Vec<Vec<Element>>>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self, E> {
+        Parser::new(src).parse()
+    }
+
+    /// Expands the rule references at the top of `stack` until it points at a character class,
+    /// pushing every resulting stack to `out`. An empty stack means the input is complete.
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        loop {
+            let Some(&top) = stack.last() else {
+                out.push(stack);
+                return;
+            };
+            let sequence = &self.rules[top.rule][top.alt];
+            if top.pos == sequence.len() {
+                stack.pop();
+                continue;
+            }
+            let rule = match &sequence[top.pos] {
+                Element::Chars { .. } => {
+                    out.push(stack);
+                    return;
+                }
+                Element::Rule(rule) => *rule,
+            };
+            // Move the caller past the reference and drop it when nothing is left of it, so that
+            // right recursive rules such as repetitions do not grow the stack.
+            stack.last_mut().unwrap().pos += 1;
+            while let Some(top) = stack.last() {
+                if top.pos < self.rules[top.rule][top.alt].len() {
+                    break;
+                }
+                stack.pop();
+            }
+            if stack.len() >= MAX_DEPTH {
+                return;
+            }
+            for alt in 0..self.rules[rule].len() {
+                let mut stack = stack.clone();
+                stack.push(Position { rule, alt, pos: 0 });
+                self.expand(stack, out);
+            }
+            return;
+        }
+    }
+
+    fn element(&self, position: Position) -> &Element {
+        &self.rules[position.rule][position.alt][position.pos]
+    }
+
+    /// The stacks left after matching `c` from any of `stacks`.
+    fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            match stack.last() {
+                Some(&top) if self.element(top).matches(c) => {
+                    let mut stack = stack.clone();
+                    stack.last_mut().unwrap().pos += 1;
+                    self.expand(stack, &mut out);
+                }
+                _ => {}
+            }
+        }
+        out.sort();
+        out.dedup();
+        out
+    }
+}
+
+/// A point in a rule, the element at `pos` of the alternative `alt` is the next one to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    rule: usize,
+    alt: usize,
+    pos: usize,
+}
+
+type Stack = Vec<Position>;
+
+/// The progress of a generation through a [`Grammar`], tracking every way the text generated so
+/// far can be parsed.
+#[derive(Debug, Clone)]
+pub struct GrammarState {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Stack>,
+}
+
+impl GrammarState {
+    pub fn new(grammar: Arc<Grammar>) -> Self {
+        let mut stacks = Vec::new();
+        for alt in 0..grammar.rules[grammar.root].len() {
+            let position = Position {
+                rule: grammar.root,
+                alt,
+                pos: 0,
+            };
+            grammar.expand(vec![position], &mut stacks);
+        }
+        Self { grammar, stacks }
+    }
+
+    /// Whether the text so far is a complete match of the grammar.
+    pub fn is_accepting(&self) -> bool {
+        self.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// Whether no more text can be added to the match.
+    pub fn is_finished(&self) -> bool {
+        self.stacks.iter().all(|stack| stack.is_empty())
+    }
+
+    /// Matches `text`, returning false and leaving the state unchanged when it does not fit the
+    /// grammar.
+    pub fn accept_str(&mut self, text: &str) -> bool {
+        let mut stacks = self.stacks.clone();
+        for c in text.chars() {
+            stacks = self.grammar.advance(&stacks, c);
+            if stacks.is_empty() {
+                return false;
+            }
+        }
+        self.stacks = stacks;
+        true
+    }
+
+    /// Matches the text of `token`, failing when the grammar does not allow it.
+    pub fn accept_token(&mut self, vocab: &TokenTrie, token: u32) -> Result<(), E> {
+        match vocab.text(token) {
+            Some(text) if self.accept_str(text) => Ok(()),
+            _ => anyhow::bail!("token {token} does not match the grammar"),
+        }
+    }
+
+    /// Masks the logits of the tokens that cannot continue the match. The `stop_tokens` are only
+    /// allowed once the match is complete.
+    pub fn mask(&self, vocab: &TokenTrie, logits: &mut [f32], stop_tokens: &[u32]) {
+        let mut allowed = vec![false; logits.len()];
+        self.allow(vocab, 0, &self.stacks, &mut allowed);
+        if self.is_accepting() {
+            for &token in stop_tokens {
+                if let Some(allowed) = allowed.get_mut(token as usize) {
+                    *allowed = true;
+                }
+            }
+        }
+        for (logit, allowed) in logits.iter_mut().zip(allowed) {
+            if !allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// Walks the vocabulary trie from `node`, allowing every token whose text can be matched.
+    fn allow(&self, vocab: &TokenTrie, node: usize, stacks: &[Stack], allowed: &mut [bool]) {
+        for &(c, child) in &vocab.nodes[node].children {
+            let stacks = self.grammar.advance(stacks, c);
+            if stacks.is_empty() {
+                continue;
+            }
+            for &token in &vocab.nodes[child].tokens {
+                if let Some(allowed) = allowed.get_mut(token as usize) {
+                    *allowed = true;
+                }
+            }
+            self.allow(vocab, child, &stacks, allowed);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    /// The tokens whose text ends at this node.
+    tokens: Vec<u32>,
+}
+
+/// The text of every token of a tokenizer, arranged as a character trie so that the tokens
+/// sharing a prefix are matched against a grammar together.
+///
+/// Special tokens and tokens that do not decode to valid UTF-8 on their own, such as partial
+/// byte fallback tokens, have no text and are never allowed by a grammar.
+#[derive(Debug)]
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+    texts: Vec<Option<String>>,
+}
+
+impl TokenTrie {
+    pub fn new(tokenizer: &Tokenizer) -> Result<Self, E> {
+        let texts = token_texts(tokenizer)?;
+        let mut nodes = vec![TrieNode::default()];
+        for (token, text) in texts.iter().enumerate() {
+            let Some(text) = text else { continue };
+            let mut node = 0;
+            for c in text.chars() {
+                node = match nodes[node].children.iter().find(|(k, _)| *k == c) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((c, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(token as u32);
+        }
+        Ok(Self { nodes, texts })
+    }
+
+    /// The text `token` adds when decoded after other tokens.
+    pub fn text(&self, token: u32) -> Option<&str> {
+        self.texts.get(token as usize)?.as_deref()
+    }
+}
+
+/// Decodes every token after an anchor token, since decoders such as sentencepiece's drop the
+/// leading space of the first token.
+fn token_texts(tokenizer: &Tokenizer) -> Result<Vec<Option<String>>, E> {
+    let anchor = tokenizer
+        .encode("a", false)
+        .map_err(E::msg)?
+        .get_ids()
+        .first()
+        .copied();
+    let prefix = match anchor {
+        Some(anchor) => tokenizer.decode(&[anchor], true).map_err(E::msg)?,
+        None => String::new(),
+    };
+    let sequences: Vec<Vec<u32>> = (0..tokenizer.get_vocab_size(true) as u32)
+        .map(|token| anchor.into_iter().chain([token]).collect())
+        .collect();
+    let sequences: Vec<&[u32]> = sequences.iter().map(|s| s.as_slice()).collect();
+    let texts = tokenizer.decode_batch(&sequences, true).map_err(E::msg)?;
+    Ok(texts
+        .into_iter()
+        .map(|text| {
+            let text = text.strip_prefix(&prefix)?;
+            Some(text.to_string()).filter(|text| !text.is_empty() && !text.contains('\u{fffd}'))
+        })
+        .collect())
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    defined: Vec<bool>,
+}
+
+impl Parser {
+    fn new(src: &str) -> Self {
+        Self {
+            src: src.chars().collect(),
+            pos: 0,
+            rules: Vec::new(),
+            names: Vec::new(),
+            ids: HashMap::new(),
+            defined: Vec::new(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Grammar, E> {
+        self.skip_space();
+        while self.pos < self.src.len() {
+            let name = self.name()?;
+            self.skip_space();
+            self.expect("::=")?;
+            let id = self.rule_id(&name);
+            if self.defined[id] {
+                return self.error(&format!("rule {name} is defined twice"));
+            }
+            self.defined[id] = true;
+            self.rules[id] = self.alternatives(&name)?;
+            self.skip_space();
+        }
+        if let Some(id) = self.defined.iter().position(|defined| !defined) {
+            anyhow::bail!("grammar references undefined rule {}", self.names[id]);
+        }
+        let Some(&root) = self.ids.get("root") else {
+            anyhow::bail!("grammar has no root rule");
+        };
+        if let Some(id) = self.left_recursive_rule() {
+            anyhow::bail!("rule {} is left recursive", self.names[id]);
+        }
+        Ok(Grammar {
+            rules: self.rules,
+            root,
+        })
+    }
+
+    /// A rule that can reach itself again before matching a character, which would expand
+    /// forever.
+    fn left_recursive_rule(&self) -> Option<usize> {
+        // The rules that can match the empty string
+        let mut nullable = vec![false; self.rules.len()];
+        let is_nullable = |nullable: &[bool], element: &Element| matches!(element, Element::Rule(id) if nullable[*id]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, alternatives) in self.rules.iter().enumerate() {
+                let empty = alternatives.iter().any(|sequence| {
+                    sequence
+                        .iter()
+                        .all(|element| is_nullable(&nullable, element))
+                });
+                if empty && !nullable[id] {
+                    nullable[id] = true;
+                    changed = true;
+                }
+            }
+        }
+        // The rules each rule can expand to first, the ones up to and including its first
+        // element that cannot be empty
+        let first: Vec<Vec<usize>> = self
+            .rules
+            .iter()
+            .map(|alternatives| {
+                alternatives
+                    .iter()
+                    .flat_map(|sequence| {
+                        let end = sequence
+                            .iter()
+                            .position(|element| !is_nullable(&nullable, element))
+                            .map_or(sequence.len(), |i| i + 1);
+                        sequence[..end].iter().filter_map(|element| match element {
+                            Element::Rule(id) => Some(*id),
+                            Element::Chars { .. } => None,
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+        // Depth first search for a cycle, `visiting` holds the rules on the current path
+        let mut visiting = vec![false; self.rules.len()];
+        let mut visited = vec![false; self.rules.len()];
+        (0..self.rules.len()).find_map(|id| find_cycle(&first, id, &mut visiting, &mut visited))
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, E> {
+        let line = self.src[..self.pos].iter().filter(|c| **c == '\n').count() + 1;
+        anyhow::bail!("grammar error on line {line}: {msg}")
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char, E> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => self.error("unexpected end of grammar"),
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), E> {
+        for expected in s.chars() {
+            if self.peek() != Some(expected) {
+                return self.error(&format!("expected {s}"));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    }
+
+    fn name(&mut self) -> Result<String, E> {
+        let start = self.pos;
+        while self.peek().is_some_and(Self::is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return self.error("expected a rule name");
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    /// Whether the input continues with the start of a new rule definition.
+    fn at_rule_start(&self) -> bool {
+        let mut pos = self.pos;
+        while self.src.get(pos).copied().is_some_and(Self::is_name_char) {
+            pos += 1;
+        }
+        if pos == self.pos {
+            return false;
+        }
+        while self.src.get(pos).is_some_and(|c| c.is_whitespace()) {
+            pos += 1;
+        }
+        self.src[pos..].starts_with(&[':', ':', '='])
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        self.new_rule(name.to_string())
+    }
+
+    fn new_rule(&mut self, name: String) -> usize {
+        let id = self.rules.len();
+        self.rules.push(Vec::new());
+        self.ids.insert(name.clone(), id);
+        self.names.push(name);
+        self.defined.push(false);
+        id
+    }
+
+    /// Adds an unnamed rule generated for a group or repetition inside `parent`.
+    fn anonymous_rule(&mut self, parent: &str, alternatives: Vec<Vec<Element>>) -> usize {
+        let id = self.new_rule(format!("{parent}_{}", self.rules.len()));
+        self.rules[id] = alternatives;
+        self.defined[id] = true;
+        id
+    }
+
+    fn alternatives(&mut self, rule: &str) -> Result<Vec<Vec<Element>>, E> {
+        let mut alternatives = vec![self.sequence(rule)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence(rule)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self, rule: &str) -> Result<Vec<Element>, E> {
+        let mut sequence = Vec::new();
+        loop {
+            self.skip_space();
+            let elements = match self.peek() {
+                None | Some('|') | Some(')') => break,
+                Some(_) if self.at_rule_start() => break,
+                Some('"') => {
+                    self.pos += 1;
+                    let mut elements = Vec::new();
+                    while self.peek() != Some('"') {
+                        let c = self.char_literal()?;
+                        elements.push(Element::Chars {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        });
+                    }
+                    self.pos += 1;
+                    elements
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    vec![self.char_class()?]
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    vec![Element::Chars {
+                        ranges: Vec::new(),
+                        negated: true,
+                    }]
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let alternatives = self.alternatives(rule)?;
+                    self.skip_space();
+                    self.expect(")")?;
+                    vec![Element::Rule(self.anonymous_rule(rule, alternatives))]
+                }
+                Some(c) if Self::is_name_char(c) => {
+                    let name = self.name()?;
+                    vec![Element::Rule(self.rule_id(&name))]
+                }
+                Some(c) => return self.error(&format!("unexpected character {c:?}")),
+            };
+            let element = match self.peek() {
+                Some(op @ ('*' | '+' | '?')) => {
+                    self.pos += 1;
+                    let element = match <[Element; 1]>::try_from(elements) {
+                        Ok([element]) => element,
+                        Err(elements) => Element::Rule(self.anonymous_rule(rule, vec![elements])),
+                    };
+                    self.repetition(rule, element, op)
+                }
+                _ => {
+                    sequence.extend(elements);
+                    continue;
+                }
+            };
+            sequence.push(element);
+        }
+        Ok(sequence)
+    }
+
+    fn repetition(&mut self, rule: &str, element: Element, op: char) -> Element {
+        let id = self.new_rule(format!("{rule}_{}", self.rules.len()));
+        let alternatives = match op {
+            // x* ::= x x* | ε
+            '*' => vec![vec![element, Element::Rule(id)], vec![]],
+            // x+ ::= x x+ | x
+            '+' => vec![vec![element.clone(), Element::Rule(id)], vec![element]],
+            // x? ::= x | ε
+            _ => vec![vec![element], vec![]],
+        };
+        self.rules[id] = alternatives;
+        self.defined[id] = true;
+        Element::Rule(id)
+    }
+
+    fn char_class(&mut self) -> Result<Element, E> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        while self.peek() != Some(']') {
+            let lo = self.char_literal()?;
+            let hi = if self.peek() == Some('-') && self.src.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                self.char_literal()?
+            } else {
+                lo
+            };
+            ranges.push((lo, hi));
+        }
+        self.pos += 1;
+        Ok(Element::Chars { ranges, negated })
+    }
+
+    fn char_literal(&mut self) -> Result<char, E> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let escaped = match self.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'x' => return self.hex_char(2),
+            'u' => return self.hex_char(4),
+            'U' => return self.hex_char(8),
+            c => c,
+        };
+        Ok(escaped)
+    }
+
+    fn hex_char(&mut self, digits: usize) -> Result<char, E> {
+        let mut value = 0;
+        for _ in 0..digits {
+            match self.next()?.to_digit(16) {
+                Some(digit) => value = value * 16 + digit,
+                None => return self.error("invalid hex escape"),
+            }
+        }
+        match char::from_u32(value) {
+            Some(c) => Ok(c),
+            None => self.error("invalid character escape"),
+        }
+    }
+}
+
+/// The first rule found twice on a path from `id` through `edges`, if any.
+fn find_cycle(
+    edges: &[Vec<usize>],
+    id: usize,
+    visiting: &mut [bool],
+    visited: &mut [bool],
+) -> Option<usize> {
+    if visiting[id] {
+        return Some(id);
+    }
+    if visited[id] {
+        return None;
+    }
+    visiting[id] = true;
+    let cycle = edges[id]
+        .iter()
+        .find_map(|&next| find_cycle(edges, next, visiting, visited));
+    visiting[id] = false;
+    visited[id] = true;
+    cycle
+}
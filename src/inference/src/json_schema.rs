@@ -0,0 +1,297 @@
+use anyhow::{Error as E, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::grammar::Grammar;
+
+const WS: &str = r#"ws ::= ([ \t\n] ws)?"#;
+
+const PRIMITIVES: [(&str, &str); 8] = [
+    ("boolean", r#"boolean ::= "true" | "false""#),
+    ("null", r#"null ::= "null""#),
+    ("integer", r#"integer ::= "-"? ([0-9] | [1-9] [0-9]+)"#),
+    (
+        "number",
+        r#"number ::= "-"? ([0-9] | [1-9] [0-9]+) ("." [0-9]+)? ([eE] [-+]? [0-9]+)?"#,
+    ),
+    (
+        "string",
+        r#"string ::= "\"" ([^"\\\x00-\x1f] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]))* "\"""#,
+    ),
+    (
+        "value",
+        r#"value ::= json-object | json-array | string | number | boolean | null"#,
+    ),
+    (
+        "json-object",
+        r#"json-object ::= "{" ws (string ws ":" ws value ws ("," ws string ws ":" ws value ws)*)? "}""#,
+    ),
+    (
+        "json-array",
+        r#"json-array ::= "[" ws (value ws ("," ws value ws)*)? "]""#,
+    ),
+];
+
+/// The primitives each primitive rule refers to.
+fn primitive_dependencies(name: &str) -> &'static [&'static str] {
+    match name {
+        "value" => &[
+            "json-object",
+            "json-array",
+            "string",
+            "number",
+            "boolean",
+            "null",
+        ],
+        "json-object" | "json-array" => &["value", "string"],
+        _ => &[],
+    }
+}
+
+impl Grammar {
+    /// Builds a grammar only matching JSON documents valid against `schema`.
+    /// See [`json_schema_grammar`] for the supported subset of JSON Schema.
+    pub fn from_json_schema(schema: &Value) -> Result<Self, E> {
+        Self::parse(&json_schema_grammar(schema)?)
+    }
+
+    /// Builds a grammar for the JSON Schema schemars derives for `T`.
+    #[cfg(feature = "schemars")]
+    pub fn for_type<T: schemars::JsonSchema>() -> Result<Self, E> {
+        let schema = serde_json::to_value(schemars::schema_for!(T))?;
+        Self::from_json_schema(&schema)
+    }
+}
+
+/// Converts a JSON Schema into the GBNF-like text format of [`Grammar::parse`].
+///
+/// Supports `type` (including lists of types), `properties` and `required`, `items`, `enum`,
+/// `const`, `anyOf`, `oneOf`, `allOf` with a single schema and local `$ref`s such as
+/// `#/definitions/Quest`. Properties are written in a fixed order and no additional properties
+/// are allowed. Other keywords, such as string formats or number ranges, are ignored.
+pub fn json_schema_grammar(schema: &Value) -> Result<String, E> {
+    let mut converter = Converter {
+        root: schema,
+        rules: Vec::new(),
+        refs: HashMap::new(),
+    };
+    let root = converter.visit(schema, "root")?;
+    let mut grammar = format!("root ::= {root}\n");
+    for (_, rule) in &converter.rules {
+        grammar.push_str(rule);
+        grammar.push('\n');
+    }
+    grammar.push_str(WS);
+    grammar.push('\n');
+    Ok(grammar)
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    /// Named rules by name, in the order they were added.
+    rules: Vec<(String, String)>,
+    /// The rule name of every `$ref` visited so far.
+    refs: HashMap<String, String>,
+}
+
+impl Converter<'_> {
+    fn has_rule(&self, name: &str) -> bool {
+        self.rules.iter().any(|(rule, _)| rule == name)
+    }
+
+    fn primitive(&mut self, name: &str) -> String {
+        if !self.has_rule(name) {
+            let (_, rule) = PRIMITIVES.iter().find(|(n, _)| *n == name).unwrap();
+            self.rules.push((name.to_string(), rule.to_string()));
+            for dependency in primitive_dependencies(name) {
+                self.primitive(dependency);
+            }
+        }
+        name.to_string()
+    }
+
+    /// Returns a GBNF expression matching `schema`, adding the rules it needs. `name` is used to
+    /// name the rules of nested schemas.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, E> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Bool(false) => anyhow::bail!("schema {name} does not allow any value"),
+            Value::Object(schema) => schema,
+            _ => anyhow::bail!("schema {name} is not an object"),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(json_literal(value));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let alternatives: Vec<String> = values.iter().map(json_literal).collect();
+            return Ok(format!("({})", alternatives.join(" | ")));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword).and_then(Value::as_array) {
+                let alternatives = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, schema)| self.visit(schema, &format!("{name}-{i}")))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(format!("({})", alternatives.join(" | ")));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf").and_then(Value::as_array) {
+            match schemas.as_slice() {
+                [schema] => return self.visit(schema, name),
+                _ => anyhow::bail!("allOf with several schemas is not supported in {name}"),
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(ty)) => self.visit_type(schema, ty, name),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|ty| match ty.as_str() {
+                        Some(ty) => self.visit_type(schema, ty, name),
+                        None => anyhow::bail!("invalid type {ty} in {name}"),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("({})", alternatives.join(" | ")))
+            }
+            Some(ty) => anyhow::bail!("invalid type {ty} in {name}"),
+            None if schema.contains_key("properties") => self.visit_type(schema, "object", name),
+            None if schema.contains_key("items") => self.visit_type(schema, "array", name),
+            None => Ok(self.primitive("value")),
+        }
+    }
+
+    fn visit_type(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        ty: &str,
+        name: &str,
+    ) -> Result<String, E> {
+        match ty {
+            "object" => self.object(schema, name),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.visit(items, &format!("{name}-item"))?,
+                    None => self.primitive("value"),
+                };
+                Ok(format!(r#""[" ws ({item} ws ("," ws {item} ws)*)? "]""#))
+            }
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(self.primitive(ty)),
+            _ => anyhow::bail!("unknown type {ty} in {name}"),
+        }
+    }
+
+    fn object(&mut self, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String, E> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok(self.primitive("json-object"));
+        };
+        let required: Vec<&str> = match schema.get("required").and_then(Value::as_array) {
+            Some(required) => required.iter().filter_map(Value::as_str).collect(),
+            None => Vec::new(),
+        };
+        let mut required_props = Vec::new();
+        let mut optional_props = Vec::new();
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{name}-{}", rule_name(key)))?;
+            let kv = format!(
+                r#"{} ws ":" ws {value}"#,
+                json_literal(&Value::from(key.as_str()))
+            );
+            if required.contains(&key.as_str()) {
+                required_props.push(kv);
+            } else {
+                optional_props.push(kv);
+            }
+        }
+
+        let mut body = required_props.join(r#" "," ws "#);
+        if required_props.is_empty() {
+            // Any subset of the optional properties, in order
+            let alternatives: Vec<String> = (0..optional_props.len())
+                .map(|i| {
+                    let mut alternative = optional_props[i].clone();
+                    for kv in &optional_props[i + 1..] {
+                        alternative.push_str(&format!(r#" ("," ws {kv})?"#));
+                    }
+                    alternative
+                })
+                .collect();
+            if !alternatives.is_empty() {
+                body = format!("({})?", alternatives.join(" | "));
+            }
+        } else {
+            for kv in &optional_props {
+                body.push_str(&format!(r#" ("," ws {kv})?"#));
+            }
+        }
+
+        let rule = self.unique_name(name);
+        let definition = format!(r#"{rule} ::= "{{" ws {body} ws "}}""#);
+        self.rules.push((rule.clone(), definition));
+        Ok(rule)
+    }
+
+    /// Resolves a local `$ref` into its own rule, so that recursive schemas are supported.
+    fn reference(&mut self, reference: &str) -> Result<String, E> {
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let Some(pointer) = reference.strip_prefix('#') else {
+            anyhow::bail!("only local references are supported, got {reference}");
+        };
+        let Some(schema) = self.root.pointer(pointer) else {
+            anyhow::bail!("unresolved reference {reference}");
+        };
+        let last = pointer.rsplit('/').next().unwrap_or("ref");
+        let rule = self.unique_name(&format!("ref-{}", rule_name(last)));
+        self.refs.insert(reference.to_string(), rule.clone());
+        // Reserve the name before visiting in case the schema refers back to itself
+        self.rules.push((rule.clone(), String::new()));
+        let expression = self.visit(schema, &rule)?;
+        let definition = format!("{rule} ::= {expression}");
+        if let Some(entry) = self.rules.iter_mut().find(|(name, _)| *name == rule) {
+            entry.1 = definition;
+        }
+        Ok(rule)
+    }
+
+    fn unique_name(&self, name: &str) -> String {
+        let mut unique = name.to_string();
+        let mut i = 1;
+        while unique == "root" || self.has_rule(&unique) {
+            unique = format!("{name}{i}");
+            i += 1;
+        }
+        unique
+    }
+}
+
+/// Turns a property or definition name into a valid rule name.
+fn rule_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// A GBNF string literal matching the JSON serialization of `value`.
+fn json_literal(value: &Value) -> String {
+    let json = value.to_string();
+    let mut literal = String::from('"');
+    for c in json.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
@@ -1,12 +1,24 @@
 // expose an inference API
+pub mod bias;
 pub mod embedding;
+pub mod generation;
+pub mod grammar;
+pub mod json_schema;
+pub mod lora;
+pub mod memory;
+pub mod models;
 pub mod prompts;
+pub mod sampling;
+pub mod session;
+pub mod source;
 pub mod text_generation;
+pub mod tools;
 
 #[cfg(test)]
 mod tests {
     use std::sync::mpsc::TryRecvError;
 
+    use crate::generation::GenerationConfig;
     use crate::text_generation::TextGeneration;
 
     use super::*;
@@ -15,6 +27,20 @@ mod tests {
     use candle_core::Tensor;
     use embedding::*;
 
+    /// The `config.json` of a tiny Llama, whose random weights the model tests build in memory.
+    fn tiny_llama_config() -> serde_json::Value {
+        serde_json::json!({
+            "model_type": "llama",
+            "hidden_size": 16,
+            "intermediate_size": 32,
+            "vocab_size": 50,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "max_position_embeddings": 32
+        })
+    }
+
     /*#[test]
     fn instantiate_embedding_model() {
         let em_result = EmbeddingModel::new(true, false, None, None);
@@ -69,6 +95,826 @@ mod tests {
         assert_eq!(similarity, 1.0);
     }*/
 
+    #[test]
+    fn test_local_source_names_missing_file() {
+        let dir = std::env::temp_dir().join("jovia_test_local_source");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+
+        let source = source::ModelSource::local(&dir).unwrap();
+        assert!(source.get("config.json").is_ok());
+        let err = source.get("tokenizer.json").unwrap_err();
+        assert!(err.to_string().contains("tokenizer.json"));
+    }
+
+    #[test]
+    fn test_local_source_loads_all_shards() {
+        let dir = std::env::temp_dir().join("jovia_test_sharded_source");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            r#"{"weight_map": {"a": "model-00002-of-00002.safetensors", "b": "model-00001-of-00002.safetensors", "c": "model-00002-of-00002.safetensors"}}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("model-00001-of-00002.safetensors"), "").unwrap();
+        std::fs::write(dir.join("model-00002-of-00002.safetensors"), "").unwrap();
+
+        let source = source::ModelSource::local(&dir).unwrap();
+        let shards = source.get_safetensors("model.safetensors").unwrap();
+        assert_eq!(
+            shards,
+            vec![
+                dir.join("model-00001-of-00002.safetensors"),
+                dir.join("model-00002-of-00002.safetensors"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weights_format() {
+        use source::WeightsFormat;
+
+        assert_eq!(
+            WeightsFormat::from_path("stories15M.bin"),
+            WeightsFormat::Llama2c
+        );
+        assert_eq!(
+            WeightsFormat::from_path("pytorch_model.bin"),
+            WeightsFormat::PyTorch
+        );
+        let shard = "pytorch_model-00001-of-00002.bin";
+        assert_eq!(WeightsFormat::from_path(shard), WeightsFormat::PyTorch);
+        assert_eq!(WeightsFormat::from_path("model.gguf"), WeightsFormat::Gguf);
+        let safetensors = "model.safetensors";
+        assert_eq!(
+            WeightsFormat::from_path(safetensors),
+            WeightsFormat::Safetensors
+        );
+    }
+
+    #[test]
+    fn test_architecture_from_config() {
+        use models::Architecture;
+
+        let config = serde_json::json!({"model_type": "qwen2"});
+        assert_eq!(
+            Architecture::from_config(&config).unwrap(),
+            Architecture::Qwen2
+        );
+        let config = serde_json::json!({"architectures": ["MistralForCausalLM"]});
+        assert_eq!(
+            Architecture::from_config(&config).unwrap(),
+            Architecture::Mistral
+        );
+        let config =
+            serde_json::json!({"model_type": "gpt2", "architectures": ["GPT2LMHeadModel"]});
+        assert!(Architecture::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_sampling_filters() {
+        use sampling::{Sampler, SamplingConfig};
+
+        let logits = [1.0, 3.0, 2.0, 0.5];
+
+        let mut sampler = Sampler::new(SamplingConfig::default());
+        assert_eq!(sampler.sample_from(&logits).unwrap(), 1);
+
+        let mut sampler = Sampler::new(SamplingConfig {
+            top_k: Some(1),
+            ..SamplingConfig::with_temperature(2.0)
+        });
+        for _ in 0..10 {
+            assert_eq!(sampler.sample_from(&logits).unwrap(), 1);
+        }
+
+        // exp(2 - 3) ~ 0.37 of the top token survives a min-p of 0.3, exp(1 - 3) ~ 0.14 does not
+        let mut sampler = Sampler::new(SamplingConfig {
+            min_p: Some(0.3),
+            ..SamplingConfig::with_temperature(1.0)
+        });
+        for _ in 0..20 {
+            assert!([1, 2].contains(&sampler.sample_from(&logits).unwrap()));
+        }
+
+        let sample = |seed| {
+            let mut sampler = Sampler::new(SamplingConfig {
+                seed,
+                ..SamplingConfig::with_temperature(1.0)
+            });
+            (0..16)
+                .map(|_| sampler.sample_from(&logits).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+    }
+
+    #[test]
+    fn test_token_logprobs() {
+        use sampling::TokenLogprobs;
+
+        let logits = [1.0, 3.0, 2.0, f32::NEG_INFINITY];
+        let logprobs = TokenLogprobs::new(&logits, 2, 2);
+        let total: f32 = logits.iter().map(|logit| logit.exp()).sum();
+        assert!((logprobs.logprob - (2f32.exp() / total).ln()).abs() < 1e-5);
+        let top: Vec<u32> = logprobs.top.iter().map(|(token, _)| *token).collect();
+        assert_eq!(top, [1, 2]);
+        // Masked tokens are never listed as alternatives
+        assert_eq!(TokenLogprobs::new(&logits, 1, 10).top.len(), 3);
+    }
+
+    #[test]
+    fn test_stop_sequences() {
+        use generation::StopSequences;
+
+        let mut stops = StopSequences::new(vec!["<|im_end|>".to_string(), "\nUser:".to_string()]);
+        assert_eq!(stops.push("Hello"), ("Hello".to_string(), false));
+        // A possible start of a stop sequence is held back until it can be decided
+        assert_eq!(stops.push(" there\nUs"), (" there".to_string(), false));
+        assert_eq!(stops.push("ually"), ("\nUsually".to_string(), false));
+        assert_eq!(stops.push(" fine<|im"), (" fine".to_string(), false));
+        assert_eq!(stops.push("_end|> more"), ("".to_string(), true));
+
+        let mut stops = StopSequences::new(vec!["END".to_string()]);
+        assert_eq!(stops.push("the EN"), ("the ".to_string(), false));
+        assert_eq!(stops.flush(), "EN");
+    }
+
+    #[test]
+    fn test_token_output_stream() -> Result<(), E> {
+        use std::str::FromStr;
+        use text_generation::TokenOutputStream;
+
+        // A Llama style tokenizer with byte fallback, whose decoder strips the leading space
+        let tokenizer = tokenizers::Tokenizer::from_str(
+            &serde_json::json!({
+                "version": "1.0",
+                "added_tokens": [],
+                "decoder": {
+                    "type": "Sequence",
+                    "decoders": [
+                        { "type": "Replace", "pattern": { "String": "▁" }, "content": " " },
+                        { "type": "ByteFallback" },
+                        { "type": "Fuse" },
+                        { "type": "Strip", "content": " ", "start": 1, "stop": 0 }
+                    ]
+                },
+                "model": {
+                    "type": "BPE",
+                    "byte_fallback": true,
+                    "vocab": {
+                        "<0xF0>": 0, "<0x9F>": 1, "<0x98>": 2, "<0x80>": 3,
+                        "▁Hello": 4, ",": 5, "▁world": 6, "!": 7
+                    },
+                    "merges": []
+                }
+            })
+            .to_string(),
+        )
+        .map_err(E::msg)?;
+        let tokens = [4, 5, 6, 7, 0, 1, 2, 3];
+
+        let mut stream = TokenOutputStream::new(tokenizer.clone());
+        let mut chunks = Vec::new();
+        for token in tokens {
+            chunks.push(stream.next_token(token)?);
+        }
+        let rest = stream.decode_rest()?;
+        // Punctuation comes out right away, the emoji once its last byte is there
+        let expected = ["Hello", ",", " world", "!", "", "", "", "😀"];
+        let chunks: Vec<_> = chunks.into_iter().map(Option::unwrap_or_default).collect();
+        assert_eq!(chunks, expected);
+        assert_eq!(rest, None);
+        let text = tokenizer.decode(&tokens, true).map_err(E::msg)?;
+        assert_eq!(chunks.concat(), text);
+
+        // The first word keeps its space when decoded after the prompt
+        let mut stream = TokenOutputStream::with_context(tokenizer.clone(), &[4]);
+        assert_eq!(stream.next_token(6)?.as_deref(), Some(" world"));
+        assert_eq!(stream.decode_all()?, " world");
+
+        // Single tokens, as shown with their logprobs
+        assert_eq!(text_generation::token_bytes(&tokenizer, 6), b" world");
+        assert_eq!(text_generation::token_bytes(&tokenizer, 1), [0x9F]);
+        let byte_level = tokenizers::Tokenizer::from_str(
+            &serde_json::json!({
+                "version": "1.0",
+                "added_tokens": [],
+                "decoder": {
+                    "type": "ByteLevel",
+                    "add_prefix_space": false,
+                    "trim_offsets": true,
+                    "use_regex": true
+                },
+                "model": { "type": "BPE", "vocab": { "Ġworld": 0, "ðŁ": 1 }, "merges": [] }
+            })
+            .to_string(),
+        )
+        .map_err(E::msg)?;
+        assert_eq!(text_generation::token_bytes(&byte_level, 0), b" world");
+        assert_eq!(text_generation::token_bytes(&byte_level, 1), [0xF0, 0x9F]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_templates() -> Result<(), E> {
+        use prompts::{ChatFormat, ChatMessage, ChatTemplate};
+        use std::str::FromStr;
+
+        let messages = [
+            ChatMessage::system("You are a pirate."),
+            ChatMessage::user("Hi!"),
+            ChatMessage::assistant("Arr."),
+            ChatMessage::user("Where is the gold?"),
+        ];
+        let render = |format: ChatFormat| format.template().render(&messages, true);
+        assert_eq!(
+            render(ChatFormat::ChatMl)?,
+            "<|im_start|>system\nYou are a pirate.<|im_end|>\n<|im_start|>user\nHi!<|im_end|>\n\
+             <|im_start|>assistant\nArr.<|im_end|>\n<|im_start|>user\nWhere is the gold?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+        assert_eq!(
+            render(ChatFormat::Llama2)?,
+            "<s>[INST] <<SYS>>\nYou are a pirate.\n<</SYS>>\n\nHi! [/INST] Arr. </s>\
+             <s>[INST] Where is the gold? [/INST]"
+        );
+        assert_eq!(
+            render(ChatFormat::Llama3)?,
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nYou are a pirate.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi!<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\nArr.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nWhere is the gold?<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(
+            render(ChatFormat::Zephyr)?,
+            "<|system|>\nYou are a pirate.</s>\n<|user|>\nHi!</s>\n<|assistant|>\nArr.</s>\n\
+             <|user|>\nWhere is the gold?</s>\n<|assistant|>\n"
+        );
+        assert_eq!(
+            render(ChatFormat::Alpaca)?,
+            "You are a pirate.\n\n### Instruction:\nHi!\n\n### Response:\nArr.</s>\n\n\
+             ### Instruction:\nWhere is the gold?\n\n### Response:\n"
+        );
+        // Llama-2 only takes conversations alternating between the user and the assistant
+        let llama2 = ChatFormat::Llama2.template();
+        assert!(llama2.render(&messages[1..2], false).is_ok());
+        assert!(llama2.render(&messages[2..], false).is_err());
+
+        // Templates from tokenizer configs use Python string methods
+        let dir = tempfile::tempdir()?;
+        let config = serde_json::json!({
+            "bos_token": { "content": "<s>" },
+            "eos_token": "</s>",
+            "chat_template": "{{ bos_token }}{% for m in messages %}{{ m.role.upper() }}: \
+                {{ m.content.strip() }}{{ eos_token }}{% endfor %}"
+        });
+        let path = dir.path().join("tokenizer_config.json");
+        std::fs::write(&path, config.to_string())?;
+        let template = ChatTemplate::from_tokenizer_config(&path)?;
+        let message = ChatMessage::user(" Ahoy ");
+        let text = template.unwrap().render(&[message], false)?;
+        assert_eq!(text, "<s>USER: Ahoy</s>");
+        // Text that is not a template, e.g. a misspelled format name
+        assert!(ChatTemplate::new("chat-ml", "", "").is_err());
+
+        // Only the special tokens the tokenizer puts before a text are added to a chat
+        let tokenizer = |single: serde_json::Value| {
+            tokenizers::Tokenizer::from_str(
+                &serde_json::json!({
+                    "version": "1.0",
+                    "added_tokens": [
+                        { "id": 0, "content": "<s>", "special": true, "single_word": false,
+                          "lstrip": false, "rstrip": false, "normalized": false },
+                        { "id": 1, "content": "</s>", "special": true, "single_word": false,
+                          "lstrip": false, "rstrip": false, "normalized": false }
+                    ],
+                    "pre_tokenizer": { "type": "WhitespaceSplit" },
+                    "post_processor": {
+                        "type": "TemplateProcessing",
+                        "single": single,
+                        "pair": [{ "Sequence": { "id": "A", "type_id": 0 } }],
+                        "special_tokens": {
+                            "<s>": { "id": "<s>", "ids": [0], "tokens": ["<s>"] },
+                            "</s>": { "id": "</s>", "ids": [1], "tokens": ["</s>"] }
+                        }
+                    },
+                    "model": {
+                        "type": "WordLevel",
+                        "vocab": { "<s>": 0, "</s>": 1, "ahoy": 2, "[UNK]": 3 },
+                        "unk_token": "[UNK]"
+                    }
+                })
+                .to_string(),
+            )
+            .map_err(E::msg)
+        };
+        let bos = serde_json::json!({ "SpecialToken": { "id": "<s>", "type_id": 0 } });
+        let eos = serde_json::json!({ "SpecialToken": { "id": "</s>", "type_id": 0 } });
+        let text = serde_json::json!({ "Sequence": { "id": "A", "type_id": 0 } });
+        let bos_only = tokenizer(serde_json::json!([bos, text]))?;
+        assert_eq!(text_generation::encode_prefixed(&bos_only, "ahoy")?, [0, 2]);
+        assert_eq!(
+            text_generation::encode_prefixed(&bos_only, "<s> ahoy")?,
+            [0, 2]
+        );
+        let eos_only = tokenizer(serde_json::json!([text, eos]))?;
+        assert_eq!(text_generation::encode_prefixed(&eos_only, "ahoy")?, [2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversation_budget() -> Result<(), E> {
+        use prompts::{ChatMessage, Conversation, HistoryOverflow};
+
+        let words = |messages: &[ChatMessage]| -> Result<usize, E> {
+            Ok(messages
+                .iter()
+                .map(|message| message.content.split_whitespace().count())
+                .sum())
+        };
+        let conversation = |overflow| {
+            let system = Some("You are Bob the blacksmith.".to_string());
+            let mut conversation = Conversation::new(system, 40, overflow);
+            conversation.pin("The player is called Ann.");
+            for i in 0..6 {
+                let question = format!("Tell me about sword {i} please.");
+                conversation.push(ChatMessage::user(question));
+                conversation.push(ChatMessage::assistant(format!("Sword {i} is sharp.")));
+            }
+            conversation.push(ChatMessage::user("And the shield?"));
+            conversation
+        };
+
+        // 14 words of system message and pinned fact, 10 per exchange and 3 for the last turn
+        let mut dropping = conversation(HistoryOverflow::Drop);
+        assert_eq!(dropping.fit_with(words, |_, _| unreachable!())?, 8);
+        assert_eq!(dropping.turns()[0].content, "Tell me about sword 4 please.");
+        let system = &dropping.messages()[0].content;
+        assert!(system.contains("The player is called Ann."));
+        assert_eq!(words(&dropping.messages())?, 37);
+
+        // The summary takes up to 5 words after its 6 word header
+        let mut summarizing = conversation(HistoryOverflow::Summarize { max_tokens: 5 });
+        let forgotten = summarizing.fit_with(words, |messages, max_tokens| {
+            assert_eq!((messages.len(), max_tokens), (10, 5));
+            Ok("Ann asked about swords.".to_string())
+        })?;
+        assert_eq!(forgotten, 10);
+        assert_eq!(summarizing.summary(), Some("Ann asked about swords."));
+        assert_eq!(summarizing.turns().len(), 3);
+        assert!(words(&summarizing.messages())? <= 40);
+
+        // Conversations that cannot fit are left as they were
+        let mut tight = conversation(HistoryOverflow::Drop);
+        tight.token_budget = 10;
+        assert!(tight.fit_with(words, |_, _| unreachable!()).is_err());
+        assert_eq!(tight.turns().len(), 13);
+        let mut long_summary = conversation(HistoryOverflow::Summarize { max_tokens: 5 });
+        let summary = "Ann asked about six swords and Bob told her they are sharp.";
+        let fit = long_summary.fit_with(words, |_, _| Ok(summary.to_string()));
+        assert!(fit.is_err());
+        assert_eq!(long_summary.turns().len(), 13);
+        assert_eq!(long_summary.summary(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tool_calls() {
+        use prompts::{Conversation, HistoryOverflow};
+        use tools::{Tool, ToolCall};
+
+        let reply = r#"Let me check. <tool_call>{"name": "open_door", "arguments": {"id": 3}}"#;
+        let call = ToolCall::parse(reply).unwrap();
+        assert_eq!(call.name, "open_door");
+        assert_eq!(call.arguments, serde_json::json!({ "id": 3 }));
+        assert!(ToolCall::parse("<tool_call>{\"name\": \"wave\"}</tool_call>").is_some());
+        assert!(ToolCall::parse("The door is open.").is_none());
+        assert_eq!(tools::visible_len("Let me check. <tool_"), 14);
+        assert_eq!(tools::visible_len(reply), 14);
+        assert_eq!(tools::visible_len("Done."), 5);
+
+        let mut conversation = Conversation::new(None, 1000, HistoryOverflow::Drop);
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "id": { "type": "integer" } }
+        });
+        conversation.set_tools(vec![Tool::new("open_door", "Opens a door", schema)]);
+        assert!(conversation.messages()[0].content.contains("\"open_door\""));
+
+        let call = conversation.push_reply(reply, |call| {
+            assert_eq!(call.arguments["id"], 3);
+            Ok(serde_json::json!("opened"))
+        });
+        assert!(call.is_some());
+        let response = &conversation.turns()[1];
+        assert_eq!(response.role, "user");
+        assert!(response.content.contains(r#""content":"opened""#));
+
+        let call = conversation.push_reply(r#"<tool_call>{"name": "fly"}"#, |_| unreachable!());
+        assert!(call.is_some());
+        let response = &conversation.turns()[3];
+        assert!(response.content.contains("no tool named fly"));
+        let call = conversation.push_reply("Done!", |_| unreachable!());
+        assert!(call.is_none());
+        assert_eq!(conversation.turns().len(), 5);
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        use bias::LogitBias;
+        use std::collections::HashMap;
+
+        let bias = LogitBias::new(HashMap::from([(0, 2.0)]), vec![vec![1], vec![5, 2]]);
+        let mut logits = vec![0.0; 4];
+        bias.apply(&mut logits, &[3, 4]);
+        assert_eq!(logits, [2.0, f32::NEG_INFINITY, 0.0, 0.0]);
+
+        // The last token of a banned sequence is only masked right after the rest of it
+        let mut logits = vec![0.0; 4];
+        bias.apply(&mut logits, &[3, 5]);
+        assert_eq!(logits[2], f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_grammar() {
+        use grammar::{Grammar, GrammarState};
+        use std::sync::Arc;
+
+        let grammar = Grammar::parse(
+            r#"
+            # a move command
+            root ::= "go " dir ("," ws dir)*
+            dir  ::= "north" | "south" | [0-9]+
+            ws   ::= [ \t]?
+            "#,
+        )
+        .unwrap();
+        let grammar = Arc::new(grammar);
+
+        let mut state = GrammarState::new(grammar.clone());
+        assert!(state.accept_str("go no"));
+        assert!(!state.is_accepting());
+        assert!(!state.accept_str("x"));
+        assert!(state.accept_str("rth, 42"));
+        assert!(state.is_accepting());
+        assert!(!state.is_finished());
+
+        let mut state = GrammarState::new(grammar);
+        assert!(!state.accept_str("go west"));
+
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "yes" | "no""#).unwrap());
+        let mut state = GrammarState::new(grammar);
+        assert!(state.accept_str("no"));
+        assert!(state.is_finished());
+
+        assert!(Grammar::parse("root ::= missing").is_err());
+        assert!(Grammar::parse(r#"start ::= "a""#).is_err());
+
+        // Left recursion is refused, also when it goes through rules that can be empty
+        assert!(Grammar::parse(r#"root ::= root "a" | "a""#).is_err());
+        let err = Grammar::parse(
+            r#"
+            root ::= list "."
+            list ::= ws list "," item | item
+            ws   ::= " "?
+            item ::= "a"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("left recursive"), "{err}");
+        assert!(Grammar::parse(r#"root ::= "a" root | "a""#).is_ok());
+    }
+
+    #[test]
+    fn test_json_schema_grammar() {
+        use grammar::{Grammar, GrammarState};
+        use std::sync::Arc;
+
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "reward": {"type": "integer"},
+                "kind": {"enum": ["fetch", "slay"]},
+                "steps": {"type": "array", "items": {"$ref": "#/definitions/Step"}}
+            },
+            "required": ["name", "kind"],
+            "definitions": {
+                "Step": {"type": "object", "properties": {"done": {"type": "boolean"}}}
+            }
+        });
+        let grammar = Arc::new(Grammar::from_json_schema(&schema).unwrap());
+        let accepts = |text: &str| {
+            let mut state = GrammarState::new(grammar.clone());
+            state.accept_str(text) && state.is_accepting()
+        };
+        assert!(accepts(r#"{"kind": "slay", "name": "Rats"}"#));
+        assert!(accepts(
+            r#"{"kind":"fetch","name":"A \"B\"","reward":-5,"steps":[{"done":true},{}]}"#
+        ));
+        assert!(!accepts(r#"{"kind": "talk", "name": "Rats"}"#));
+        assert!(!accepts(r#"{"name": "Rats"}"#));
+        assert!(!accepts(
+            r#"{"kind": "slay", "name": "Rats", "reward": 1.5}"#
+        ));
+    }
+
+    #[test]
+    fn test_llama_cache_rollback() -> Result<(), E> {
+        use candle_core::{DType, Device};
+        use candle_nn::{VarBuilder, VarMap};
+        use models::attention::Cache;
+        use models::llama::{Config, Llama};
+
+        let config: Config = serde_json::from_value(tiny_llama_config())?;
+        let varmap = VarMap::new();
+        let model = Llama::load(
+            VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu),
+            &config,
+        )?;
+        let input = |tokens: &[u32]| Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0);
+        let tokens = [1, 5, 7, 3, 9, 2];
+
+        let mut cache = Cache::with_layers(config.num_hidden_layers);
+        let expected = model.forward_all(&input(&tokens)?, 0, &mut cache)?;
+
+        // Feed a prefix and two rejected tokens, then the rest after rolling them back
+        let mut cache = Cache::with_layers(config.num_hidden_layers);
+        model.forward(&input(&tokens[..2])?, 0, &mut cache)?;
+        model.forward_all(&input(&[4, 4])?, 2, &mut cache)?;
+        cache.truncate(2)?;
+        assert_eq!(cache.len(), 2);
+        let logits = model.forward_all(&input(&tokens[2..])?, 2, &mut cache)?;
+
+        let diff = (expected.narrow(1, 2, 4)? - logits)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_second_turn() -> Result<(), E> {
+        use candle_core::{DType, Device};
+        use candle_nn::{VarBuilder, VarMap};
+        use session::Session;
+
+        // The fields the other architectures require on top of the tiny Llama's
+        let common = serde_json::json!({
+            "hidden_act": "silu",
+            "rms_norm_eps": 1e-5,
+            "layer_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "tie_word_embeddings": false
+        });
+        let config = |extra: serde_json::Value| {
+            let mut config = tiny_llama_config();
+            for fields in [common.clone(), extra] {
+                if let (Some(config), serde_json::Value::Object(fields)) =
+                    (config.as_object_mut(), fields)
+                {
+                    config.extend(fields);
+                }
+            }
+            config
+        };
+        let configs = [
+            config(serde_json::json!({ "model_type": "llama" })),
+            config(serde_json::json!({ "model_type": "mistral", "sliding_window": 32 })),
+            config(serde_json::json!({
+                "model_type": "qwen2",
+                "sliding_window": 32,
+                "max_window_layers": 2,
+                "use_sliding_window": false
+            })),
+            config(serde_json::json!({
+                "model_type": "gemma",
+                "attention_bias": false,
+                "head_dim": 4
+            })),
+            config(serde_json::json!({
+                "model_type": "stablelm",
+                "partial_rotary_factor": 0.5,
+                "use_cache": true
+            })),
+            config(serde_json::json!({
+                "model_type": "phi",
+                "hidden_act": "gelu_new",
+                "partial_rotary_factor": 0.5,
+                "qk_layernorm": false
+            })),
+        ];
+        for config in configs {
+            let varmap = VarMap::new();
+            let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+            let model = models::from_var_builder(config, vb)?;
+
+            let mut whole = Session::new(model.fork()?);
+            whole.append_tokens(&[1, 5, 7, 3, 9, 2]);
+            let (expected, _) = whole.forward_pending(&Device::Cpu)?;
+
+            // The second turn feeds several tokens at once after the first one
+            let mut session = Session::new(model);
+            session.append_tokens(&[1, 5, 7]);
+            session.forward_pending(&Device::Cpu)?;
+            session.append_tokens(&[3, 9, 2]);
+            let (logits, fed) = session.forward_pending(&Device::Cpu)?;
+            assert_eq!(fed, 3);
+
+            let diff = (expected - logits)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_cache_capacity() -> Result<(), E> {
+        use candle_core::{DType, Device};
+        use candle_nn::{VarBuilder, VarMap};
+        use session::{PrefixCache, Session};
+
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let model = models::from_var_builder(tiny_llama_config(), vb)?;
+
+        let mut cache = PrefixCache::with_capacity(2);
+        cache.insert(vec![1], Session::new(model.fork()?));
+        cache.insert(vec![2], Session::new(model.fork()?));
+        // Using the first prefix makes the second one the least recently used
+        assert!(cache.get(&[1]).is_some());
+        cache.insert(vec![3], Session::new(model.fork()?));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&[1]) && !cache.contains(&[2]) && cache.contains(&[3]));
+
+        cache.set_capacity(1);
+        assert!(cache.contains(&[3]) && !cache.contains(&[1]));
+        cache.set_capacity(0);
+        cache.insert(vec![4], Session::new(model));
+        assert!(cache.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_estimate() -> Result<(), E> {
+        use candle_core::{DType, Device};
+        use candle_nn::{VarBuilder, VarMap};
+        use memory::MemoryEstimate;
+        use models::llama::{Config, Llama};
+
+        let json = tiny_llama_config();
+        let config: Config = serde_json::from_value(json.clone())?;
+        let varmap = VarMap::new();
+        Llama::load(
+            VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu),
+            &config,
+        )?;
+        let parameters: usize = varmap.all_vars().iter().map(|var| var.elem_count()).sum();
+
+        let estimate = MemoryEstimate::from_config(&json, DType::F16, None)?;
+        assert_eq!(estimate.weights, parameters as u64 * 2);
+        // Keys and values of 2 layers with 2 heads of 4 values for 32 tokens
+        assert_eq!(estimate.kv_cache, 2 * 2 * 2 * 4 * 32 * 2);
+        let estimate = MemoryEstimate::from_config(&json, DType::F32, Some(8))?;
+        assert_eq!(estimate.kv_cache, 2 * 2 * 2 * 4 * 8 * 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lora() -> Result<(), E> {
+        use candle_core::{DType, Device};
+        use candle_nn::{VarBuilder, VarMap};
+        use lora::LoraAdapter;
+        use models::attention::Cache;
+        use models::llama::{Config, Llama};
+        use std::collections::HashMap;
+
+        let config: Config = serde_json::from_value(tiny_llama_config())?;
+        let varmap = VarMap::new();
+        let device = Device::Cpu;
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let base = Llama::load(vb, &config)?;
+
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let adapter_config = serde_json::json!({ "r": 2, "lora_alpha": 4 });
+        std::fs::write(dir.join("adapter_config.json"), adapter_config.to_string())?;
+        let layer = "base_model.model.model.layers.1.self_attn.v_proj";
+        let a = Tensor::randn(0f32, 1., (2, 16), &device)?;
+        // The 2 key and value heads of 4 values
+        let b = Tensor::randn(0f32, 1., (8, 2), &device)?;
+        let weights = HashMap::from([
+            (format!("{layer}.lora_A.weight"), a),
+            (format!("{layer}.lora_B.weight"), b),
+        ]);
+        candle_core::safetensors::save(&weights, dir.join("adapter_model.safetensors"))?;
+        let adapter = LoraAdapter::from_source(&source::ModelSource::local(dir)?, &device)?;
+
+        let input = Tensor::new(&[1u32, 5, 7, 3], &device)?.unsqueeze(0)?;
+        let logits = |model: &Llama| {
+            model.forward(&input, 0, &mut Cache::with_layers(config.num_hidden_layers))
+        };
+        let max_diff = |a: Tensor, b: Tensor| -> Result<f32, E> {
+            Ok((a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?)
+        };
+        let unmerged = logits(&base.with_lora(&adapter, false)?)?;
+        let merged = logits(&base.with_lora(&adapter, true)?)?;
+        assert!(max_diff(unmerged.clone(), merged)? < 1e-4);
+        assert!(max_diff(unmerged, logits(&base)?)? > 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_overflow() -> Result<(), E> {
+        use candle_core::{DType, Device};
+        use models::CausalLm;
+        use session::{ContextOverflow, Session};
+
+        /// Stands in for a model, only keeping track of the length of its KV cache.
+        struct CacheOnly {
+            cached: usize,
+        }
+
+        impl CausalLm for CacheOnly {
+            fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+                assert_eq!(index_pos, self.cached);
+                self.cached += input.dim(1)?;
+                Ok(Tensor::zeros((1, 8), DType::F32, &Device::Cpu)?)
+            }
+
+            fn truncate(&mut self, len: usize) -> Result<(), E> {
+                self.cached = len;
+                Ok(())
+            }
+
+            fn can_truncate(&self) -> bool {
+                true
+            }
+
+            fn reset(&mut self) -> Result<(), E> {
+                self.cached = 0;
+                Ok(())
+            }
+
+            fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+                Ok(Box::new(Self {
+                    cached: self.cached,
+                }))
+            }
+
+            fn vocab_size(&self) -> usize {
+                8
+            }
+
+            fn context_length(&self) -> usize {
+                16
+            }
+        }
+
+        let mut session = Session::new(Box::new(CacheOnly { cached: 0 }));
+        session.append_tokens(&[1; 4]);
+        session.append_tokens(&[2; 5]);
+        session.append_tokens(&[3; 5]);
+        session.append_tokens(&[4; 2]);
+        session.forward_pending(&Device::Cpu)?;
+        assert!(session.is_full());
+        assert!(!session.make_room(ContextOverflow::Error)?);
+
+        // Dropping the second turn frees a quarter of the context
+        assert!(session.make_room(ContextOverflow::TruncateOldest)?);
+        assert_eq!(session.tokens(), [1, 1, 1, 1, 3, 3, 3, 3, 3, 4, 4]);
+        // Only the tokens after the system prompt are fed again
+        session.forward_pending(&Device::Cpu)?;
+
+        session.append_tokens(&[5; 5]);
+        assert!(session.make_room(ContextOverflow::Shift)?);
+        assert_eq!(session.tokens(), [1, 1, 1, 1, 4, 5, 5, 5, 5, 5]);
+        session.forward_pending(&Device::Cpu)?;
+
+        // A conversation formatted again only feeds the tokens after the shared ones
+        assert_eq!(session.replace_tokens(&[1, 1, 1, 1, 4, 6, 6])?, 2);
+        assert_eq!(session.tokens(), [1, 1, 1, 1, 4, 6, 6]);
+        let (_, fed) = session.forward_pending(&Device::Cpu)?;
+        assert_eq!(fed, 2);
+        assert_eq!(session.replace_tokens(&[1, 1, 1, 1, 4, 6, 6])?, 1);
+        session.forward_pending(&Device::Cpu)?;
+
+        // Tokens sampled ahead but never returned are dropped from the KV cache too
+        session.truncate(5)?;
+        assert_eq!(session.tokens(), [1, 1, 1, 1, 4]);
+        session.append_tokens(&[7]);
+        let (_, fed) = session.forward_pending(&Device::Cpu)?;
+        assert_eq!(fed, 1);
+        Ok(())
+    }
+
     #[test]
     fn test_textgeneration_run() -> Result<(), anyhow::Error> {
         use std::time::Instant;
@@ -80,71 +926,51 @@ How many helicopters can a human eat in one sitting?</s>
 <|assistant|>
 "
         .to_string();
-        let repeat_penalty = 1.1;
-        let repeat_last_n = 64;
-        let sample_len = 255;
+        let config = GenerationConfig {
+            max_new_tokens: 255,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            ..Default::default()
+        };
 
         println!("Loading model");
         let now = Instant::now();
-        let mut pipeline = TextGeneration::new(
+        let pipeline = TextGeneration::new(
             "TinyLlama/TinyLlama-1.1B-Chat-v1.0".to_string(),
             "model.safetensors".to_string(),
             None,
             None,
             None,
             None,
+            None,
         )
         .unwrap();
 
-        let eos_token = pipeline.tokenize("</s>".to_string())?[0];
-
-        let mut tokens = pipeline
-            .tokenizer
-            .encode(prompt.clone(), true)
-            .map_err(E::msg)?
-            .get_ids()
-            .to_vec();
-
         let elapsed = now.elapsed();
         println!("Took {:.2?} to load model", elapsed);
-        let _ = now;
-        let _ = elapsed;
 
         println!("Starting the inference loop");
         println!("{prompt:?}");
-        // Inference loop
-        let mut index_pos = 0;
-        let mut tokens_generated = 0;
         let now = Instant::now();
-        for i in 0..sample_len {
-            let (context_size, context_index) = if pipeline.cache.use_kv_cache && i > 0 {
-                (1, index_pos)
-            } else {
-                (tokens.len(), 0)
-            };
-
-            let (token, ctxt_len) = pipeline.next_token(
-                &tokens,
-                repeat_penalty,
-                repeat_last_n,
-                context_size,
-                context_index,
-            )?;
-
-            index_pos += ctxt_len;
-
-            println!("{:?}", pipeline.decode(&[token.clone()]));
-            tokens_generated += 1;
-            tokens.push(token);
-            if token == eos_token {
-                // If we get an eos token we stop generating
-                break;
-            }
+        let mut generation = pipeline.generate(&prompt, config)?;
+        for chunk in generation.by_ref() {
+            println!("{:?}", chunk?);
         }
+        let summary = generation.summary().unwrap();
+        let tokens = generation.tokens().to_vec();
         let elapsed = now.elapsed();
 
         println!("Took {:.2?} to complete inference", elapsed);
-        println!("{:?} tok/s", tokens_generated as u64 / elapsed.as_secs());
+        println!(
+            "{} prompt tokens in {:.2?}, first token after {:.2?}",
+            summary.prompt_tokens, summary.prefill_time, summary.time_to_first_token
+        );
+        println!(
+            "{} tokens at {:.2} tok/s",
+            summary.generated_tokens,
+            summary.decode_tokens_per_second()
+        );
+        println!("Stopped because of {:?}", summary.stop_reason);
         println!("Generated:");
         println!("{tokens:?}");
         let generated_text = pipeline.decode(&tokens);
@@ -0,0 +1,131 @@
+use anyhow::{Error as E, Result};
+use candle_core::{Device, Module, Tensor};
+use candle_nn::Linear;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::source::ModelSource;
+
+/// The fields of a PEFT `adapter_config.json` needed to apply the adapter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoraConfig {
+    pub r: usize,
+    pub lora_alpha: f64,
+    #[serde(default)]
+    pub use_rslora: bool,
+}
+
+impl LoraConfig {
+    /// The factor the output of the adapter is multiplied by.
+    pub fn scale(&self) -> f64 {
+        if self.use_rslora {
+            self.lora_alpha / (self.r as f64).sqrt()
+        } else {
+            self.lora_alpha / self.r as f64
+        }
+    }
+}
+
+/// A LoRA adapter saved by PEFT, holding the low rank `A` and `B` matrices of every linear layer
+/// it adapts. Adapters are small so several of them can be kept around and swapped on a single
+/// base model, see [`TextGeneration::apply_lora`](crate::text_generation::TextGeneration).
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    config: LoraConfig,
+    /// `(A, B)` keyed by the path of the adapted layer, e.g. `model.layers.0.self_attn.q_proj`.
+    layers: HashMap<String, (Tensor, Tensor)>,
+}
+
+impl LoraAdapter {
+    /// Loads `adapter_config.json` and `adapter_model.safetensors` from `source`.
+    pub fn from_source(source: &ModelSource, device: &Device) -> Result<Self, E> {
+        let config = source.get("adapter_config.json")?;
+        let weights = source.get("adapter_model.safetensors")?;
+        Self::load(&config, &weights, device)
+    }
+
+    pub fn load(config: &Path, weights: &Path, device: &Device) -> Result<Self, E> {
+        let config: LoraConfig = serde_json::from_reader(std::fs::File::open(config)?)?;
+        let mut a = HashMap::new();
+        let mut b = HashMap::new();
+        for (name, tensor) in candle_core::safetensors::load(weights, device)? {
+            let name = name.strip_prefix("base_model.model.").unwrap_or(&name);
+            if let Some(layer) = name.strip_suffix(".lora_A.weight") {
+                a.insert(layer.to_string(), tensor);
+            } else if let Some(layer) = name.strip_suffix(".lora_B.weight") {
+                b.insert(layer.to_string(), tensor);
+            }
+        }
+        let mut layers = HashMap::new();
+        for (layer, a) in a {
+            let Some(b) = b.remove(&layer) else {
+                anyhow::bail!("no lora_B weight for {layer}");
+            };
+            layers.insert(layer, (a, b));
+        }
+        if layers.is_empty() {
+            anyhow::bail!("no LoRA weights in {}", weights.display());
+        }
+        Ok(Self { config, layers })
+    }
+
+    pub fn config(&self) -> &LoraConfig {
+        &self.config
+    }
+
+    /// The paths of the layers the adapter applies to.
+    pub fn layers(&self) -> impl Iterator<Item = &str> {
+        self.layers.keys().map(String::as_str)
+    }
+}
+
+/// A linear layer without bias that a LoRA adapter can be applied to.
+#[derive(Debug, Clone)]
+pub(crate) struct LoraLinear {
+    linear: Linear,
+    /// The `A` and scaled `B` matrices of an adapter applied without merging.
+    lora: Option<(Tensor, Tensor)>,
+}
+
+impl LoraLinear {
+    pub(crate) fn new(linear: Linear) -> Self {
+        Self { linear, lora: None }
+    }
+
+    /// The layer with the weights `adapter` has for `path`, added to the layer's weight when
+    /// `merge` is set and applied on every forward pass otherwise. Returns the layer unchanged
+    /// when the adapter does not cover it.
+    pub(crate) fn with_adapter(
+        &self,
+        adapter: &LoraAdapter,
+        path: &str,
+        merge: bool,
+    ) -> candle_core::Result<Self> {
+        let Some((a, b)) = adapter.layers.get(path) else {
+            return Ok(self.clone());
+        };
+        let weight = self.linear.weight();
+        let a = a.to_dtype(weight.dtype())?;
+        let b = (b.to_dtype(weight.dtype())? * adapter.config.scale())?;
+        if merge {
+            let weight = (weight + b.matmul(&a)?)?;
+            Ok(Self::new(Linear::new(weight, None)))
+        } else {
+            Ok(Self {
+                linear: self.linear.clone(),
+                lora: Some((a, b)),
+            })
+        }
+    }
+}
+
+impl Module for LoraLinear {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let y = self.linear.forward(x)?;
+        match &self.lora {
+            Some((a, b)) => y + x.broadcast_matmul(&a.t()?)?.broadcast_matmul(&b.t()?)?,
+            None => Ok(y),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{Error as E, Result};
+use candle_core::quantized::gguf_file;
+use candle_core::DType;
+use std::path::Path;
+
+use crate::models::{self, Architecture};
+use crate::source::{ModelSource, WeightsFormat, PYTORCH_UNSUPPORTED};
+
+/// The memory a model takes once loaded, worked out from its config and file headers without
+/// loading the weights. Activations and the tokenizer are not counted, so the real use is
+/// somewhat higher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// The bytes taken by the weights.
+    pub weights: u64,
+    /// The bytes taken by the KV cache of a single session holding `context_length` tokens.
+    pub kv_cache: u64,
+    pub context_length: usize,
+}
+
+impl MemoryEstimate {
+    /// Estimates the memory `which_model` from `source` takes when loaded as `dtype`, with a KV
+    /// cache for `context_length` tokens or for the model's whole context window when `None`.
+    ///
+    /// Safetensors models only need their `config.json`, so this can be called before
+    /// downloading the weights. GGUF and llama2.c weights carry their config in the file itself,
+    /// they keep their own precision and ignore `dtype`.
+    pub fn from_source(
+        source: &ModelSource,
+        which_model: &str,
+        dtype: DType,
+        context_length: Option<usize>,
+    ) -> Result<Self, E> {
+        match WeightsFormat::from_path(which_model) {
+            WeightsFormat::Safetensors => {
+                let config = source.get("config.json")?;
+                let config: serde_json::Value = serde_json::from_slice(&std::fs::read(config)?)?;
+                Self::from_config(&config, dtype, context_length)
+            }
+            WeightsFormat::Gguf => Self::from_gguf(&source.get(which_model)?, context_length),
+            WeightsFormat::Llama2c => Self::from_llama2c(&source.get(which_model)?, context_length),
+            WeightsFormat::PyTorch => anyhow::bail!(PYTORCH_UNSUPPORTED),
+        }
+    }
+
+    /// Estimates the memory of a safetensors model from its `config.json`.
+    pub fn from_config(
+        config: &serde_json::Value,
+        dtype: DType,
+        context_length: Option<usize>,
+    ) -> Result<Self, E> {
+        let architecture = Architecture::from_config(config)?;
+        let get = |key: &str| config.get(key).and_then(|v| v.as_u64());
+        let require = |key: &str| match get(key) {
+            Some(value) => Ok(value),
+            None => anyhow::bail!("no {key} in config"),
+        };
+        let hidden_size = require("hidden_size")?;
+        let intermediate_size = require("intermediate_size")?;
+        let layers = require("num_hidden_layers")?;
+        let vocab_size = require("vocab_size")?;
+        let heads = require("num_attention_heads")?;
+        let kv_heads = get("num_key_value_heads").unwrap_or(heads);
+        let head_dim = get("head_dim").unwrap_or(hidden_size / heads);
+        let max_context = get("max_position_embeddings")
+            .or_else(|| get("n_positions"))
+            .unwrap_or(2048);
+        // Gemma ties its embeddings without saying so in its config
+        let tied = config
+            .get("tie_word_embeddings")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(architecture == Architecture::Gemma);
+
+        let attention = 2 * hidden_size * heads * head_dim + 2 * hidden_size * kv_heads * head_dim;
+        // Phi has a plain two layer MLP, the others a gated one
+        let mlp = match architecture {
+            Architecture::Phi => 2 * hidden_size * intermediate_size,
+            _ => 3 * hidden_size * intermediate_size,
+        };
+        let embeddings = vocab_size * hidden_size * if tied { 1 } else { 2 };
+        let parameters = embeddings + layers * (attention + mlp + 2 * hidden_size) + hidden_size;
+
+        let context_length = context_length.unwrap_or(max_context as usize);
+        let bytes = dtype.size_in_bytes() as u64;
+        Ok(Self {
+            weights: parameters * bytes,
+            kv_cache: kv_cache(layers, kv_heads * head_dim, context_length, bytes),
+            context_length,
+        })
+    }
+
+    /// Quantized weights take as much memory as they take on disk, the KV cache is kept in f32.
+    fn from_gguf(path: &Path, context_length: Option<usize>) -> Result<Self, E> {
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file).map_err(|err| err.with_path(path))?;
+        let md_get = |key: &str| match content.metadata.get(key) {
+            Some(value) => Ok(value.to_u32()? as u64),
+            None => anyhow::bail!("no {key} in {path:?}"),
+        };
+        let layers = md_get("llama.block_count")?;
+        let embedding_length = md_get("llama.embedding_length")?;
+        let heads = md_get("llama.attention.head_count")?;
+        let kv_heads = md_get("llama.attention.head_count_kv").unwrap_or(heads);
+        let context_length = match context_length {
+            Some(context_length) => context_length,
+            None => md_get("llama.context_length").unwrap_or(2048) as usize,
+        };
+        let weights = file.metadata()?.len() - content.tensor_data_offset;
+        let kv_dim = kv_heads * (embedding_length / heads);
+        Ok(Self {
+            weights,
+            kv_cache: kv_cache(layers, kv_dim, context_length, 4),
+            context_length,
+        })
+    }
+
+    /// llama2.c checkpoints hold f32 weights after a small header.
+    fn from_llama2c(path: &Path, context_length: Option<usize>) -> Result<Self, E> {
+        let mut file = std::fs::File::open(path)?;
+        let config = models::read_llama2c_config(&mut file)?;
+        let context_length = context_length.unwrap_or(config.seq_len);
+        let kv_dim = config.n_kv_heads * (config.dim / config.n_heads);
+        Ok(Self {
+            weights: file.metadata()?.len(),
+            kv_cache: kv_cache(config.n_layers as u64, kv_dim as u64, context_length, 4),
+            context_length,
+        })
+    }
+
+    pub fn total(&self) -> u64 {
+        self.weights + self.kv_cache
+    }
+}
+
+/// The keys and values of `layers` layers with `kv_dim` values each per token.
+fn kv_cache(layers: u64, kv_dim: u64, context_length: usize, bytes: u64) -> u64 {
+    2 * layers * kv_dim * context_length as u64 * bytes
+}
@@ -0,0 +1,625 @@
+use anyhow::{Error as E, Result};
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{
+    gemma, llama2_c, llama2_c_weights, mistral, phi, quantized_llama, qwen2,
+};
+use std::sync::Arc;
+
+use crate::lora::LoraAdapter;
+use crate::source::{ModelFiles, WeightsFormat, PYTORCH_UNSUPPORTED};
+
+pub(crate) mod attention;
+pub(crate) mod llama;
+pub(crate) mod stable_lm;
+
+/// A causal language model along with its KV cache.
+pub trait CausalLm: Send {
+    /// Runs the model over `input`, a `(batch, seq_len)` tensor of token ids starting at position
+    /// `index_pos`, and returns the `(batch, vocab_size)` f32 logits for the last position.
+    ///
+    /// Sessions feed every new turn in one call, so several tokens at a non-zero `index_pos` must
+    /// work. Models whose attention mask only covers a prompt fed from position 0 fall back to
+    /// feeding such input one token at a time.
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E>;
+
+    /// Like [`CausalLm::forward`] but returns the `(batch, seq_len, vocab_size)` logits for every
+    /// position. Models that only return the last position feed `input` one token at a time.
+    fn forward_all(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        forward_each(input, index_pos, |input, index_pos| {
+            self.forward(input, index_pos)
+        })
+    }
+
+    /// Drops the KV cache entries from position `len` on, see [`CausalLm::can_truncate`].
+    fn truncate(&mut self, _len: usize) -> Result<(), E> {
+        anyhow::bail!("this model does not support truncating its KV cache")
+    }
+
+    /// Whether [`CausalLm::truncate`] is supported.
+    fn can_truncate(&self) -> bool {
+        false
+    }
+
+    /// Clears the KV cache so that the next forward starts a new sequence.
+    fn reset(&mut self) -> Result<(), E>;
+
+    /// Applies `adapter` to the base weights, replacing any adapter applied before, and clears
+    /// the KV cache. A merged adapter is added to the weights, which runs as fast as the base
+    /// model but takes memory for a copy of every adapted weight.
+    fn apply_lora(&mut self, _adapter: &LoraAdapter, _merge: bool) -> Result<(), E> {
+        anyhow::bail!("this model does not support LoRA adapters")
+    }
+
+    /// Goes back to the base weights and clears the KV cache.
+    fn remove_lora(&mut self) -> Result<(), E> {
+        anyhow::bail!("this model does not support LoRA adapters")
+    }
+
+    /// Returns a copy of the model sharing its weights but holding its own copy of the KV cache.
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E>;
+
+    fn vocab_size(&self) -> usize;
+
+    /// The maximum number of positions the model can attend to.
+    fn context_length(&self) -> usize;
+}
+
+/// The model architectures that can be loaded from safetensors weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Llama,
+    Mistral,
+    Phi,
+    Qwen2,
+    Gemma,
+    StableLm,
+}
+
+impl Architecture {
+    /// Detects the architecture from the `model_type` field of a `config.json`, falling back to
+    /// its `architectures` list.
+    pub fn from_config(config: &serde_json::Value) -> Result<Self, E> {
+        if let Some(model_type) = config.get("model_type").and_then(|v| v.as_str()) {
+            if let Some(arch) = Self::from_model_type(model_type) {
+                return Ok(arch);
+            }
+        }
+        let architectures = config
+            .get("architectures")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_str());
+        for architecture in architectures {
+            if let Some(arch) = Self::from_class_name(architecture) {
+                return Ok(arch);
+            }
+        }
+        anyhow::bail!("unsupported model architecture in config: {config}")
+    }
+
+    fn from_model_type(model_type: &str) -> Option<Self> {
+        match model_type {
+            "llama" => Some(Self::Llama),
+            "mistral" => Some(Self::Mistral),
+            "phi" => Some(Self::Phi),
+            "qwen2" => Some(Self::Qwen2),
+            "gemma" => Some(Self::Gemma),
+            "stablelm" | "stablelm_epoch" => Some(Self::StableLm),
+            _ => None,
+        }
+    }
+
+    fn from_class_name(name: &str) -> Option<Self> {
+        match name {
+            "LlamaForCausalLM" => Some(Self::Llama),
+            "MistralForCausalLM" => Some(Self::Mistral),
+            "PhiForCausalLM" => Some(Self::Phi),
+            "Qwen2ForCausalLM" => Some(Self::Qwen2),
+            "GemmaForCausalLM" => Some(Self::Gemma),
+            "StableLmForCausalLM" | "StableLMEpochForCausalLM" => Some(Self::StableLm),
+            _ => None,
+        }
+    }
+}
+
+/// Feeds `input` one position at a time, returning the `(batch, seq_len, vocab_size)` logits.
+fn forward_each(
+    input: &Tensor,
+    index_pos: usize,
+    mut forward: impl FnMut(&Tensor, usize) -> Result<Tensor, E>,
+) -> Result<Tensor, E> {
+    let logits = (0..input.dim(1)?)
+        .map(|i| forward(&input.narrow(1, i, 1)?, index_pos + i))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Tensor::stack(&logits, 1)?)
+}
+
+/// Loads the model described by `files`, picking the implementation from the weights format and,
+/// for safetensors, from the architecture named in `config.json`.
+pub fn load(files: &ModelFiles, dtype: DType, device: &Device) -> Result<Box<dyn CausalLm>, E> {
+    let model: Box<dyn CausalLm> = match files.format() {
+        WeightsFormat::Gguf => Box::new(QuantizedLlamaModel::load(&files.weights[0], device)?),
+        WeightsFormat::Llama2c => Box::new(Llama2cModel::load(&files.weights[0], device)?),
+        WeightsFormat::PyTorch => anyhow::bail!(PYTORCH_UNSUPPORTED),
+        WeightsFormat::Safetensors => {
+            let config: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(files.config()?)?)?;
+            let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files.weights, dtype, device)? };
+            from_var_builder(config, vb)?
+        }
+    };
+    Ok(model)
+}
+
+/// Builds the architecture named in `config`, the contents of a `config.json`, from the weights
+/// in `vb`.
+pub(crate) fn from_var_builder(
+    config: serde_json::Value,
+    vb: VarBuilder<'static>,
+) -> Result<Box<dyn CausalLm>, E> {
+    let info = ConfigInfo::from_config(&config)?;
+    let model: Box<dyn CausalLm> = match Architecture::from_config(&config)? {
+        Architecture::Llama => Box::new(LlamaModel::load(config, vb)?),
+        Architecture::Mistral => {
+            let model = mistral::Model::new(&serde_json::from_value(config)?, vb)?;
+            Box::new(MistralModel { model, info })
+        }
+        Architecture::Phi => {
+            let model = phi::Model::new(&serde_json::from_value(config)?, vb)?;
+            Box::new(PhiModel { model, info })
+        }
+        Architecture::Qwen2 => {
+            let model = qwen2::Model::new(&serde_json::from_value(config)?, vb)?;
+            Box::new(Qwen2Model { model, info })
+        }
+        Architecture::Gemma => {
+            let model = gemma::Model::new(&serde_json::from_value(config)?, vb)?;
+            Box::new(GemmaModel { model, info })
+        }
+        Architecture::StableLm => Box::new(StableLmModel::load(config, vb)?),
+    };
+    Ok(model)
+}
+
+/// The config values needed by every architecture, read directly from `config.json` as some
+/// candle configs keep their fields private.
+#[derive(Debug, Clone, Copy)]
+struct ConfigInfo {
+    vocab_size: usize,
+    context_length: usize,
+}
+
+impl ConfigInfo {
+    fn from_config(config: &serde_json::Value) -> Result<Self, E> {
+        let get = |key: &str| config.get(key).and_then(|v| v.as_u64()).map(|v| v as usize);
+        let vocab_size = match get("vocab_size") {
+            Some(vocab_size) => vocab_size,
+            None => anyhow::bail!("no vocab_size in config"),
+        };
+        let context_length = match get("max_position_embeddings").or_else(|| get("n_positions")) {
+            Some(context_length) => context_length,
+            None => anyhow::bail!("no max_position_embeddings in config"),
+        };
+        Ok(Self {
+            vocab_size,
+            context_length,
+        })
+    }
+}
+
+pub struct LlamaModel {
+    /// The weights without any LoRA adapter.
+    base: Arc<llama::Llama>,
+    model: Arc<llama::Llama>,
+    cache: attention::Cache,
+    config: llama::Config,
+}
+
+impl LlamaModel {
+    fn load(config: serde_json::Value, vb: VarBuilder) -> Result<Self, E> {
+        let config: llama::Config = serde_json::from_value(config)?;
+        let model = Arc::new(llama::Llama::load(vb, &config)?);
+        Ok(Self {
+            base: model.clone(),
+            model,
+            cache: attention::Cache::with_layers(config.num_hidden_layers),
+            config,
+        })
+    }
+}
+
+impl CausalLm for LlamaModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        Ok(self.model.forward(input, index_pos, &mut self.cache)?)
+    }
+
+    fn forward_all(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        Ok(self.model.forward_all(input, index_pos, &mut self.cache)?)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<(), E> {
+        Ok(self.cache.truncate(len)?)
+    }
+
+    fn can_truncate(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        self.cache = attention::Cache::with_layers(self.config.num_hidden_layers);
+        Ok(())
+    }
+
+    fn apply_lora(&mut self, adapter: &LoraAdapter, merge: bool) -> Result<(), E> {
+        self.model = Arc::new(self.base.with_lora(adapter, merge)?);
+        self.reset()
+    }
+
+    fn remove_lora(&mut self) -> Result<(), E> {
+        self.model = self.base.clone();
+        self.reset()
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        Ok(Box::new(Self {
+            base: self.base.clone(),
+            model: self.model.clone(),
+            cache: self.cache.clone(),
+            config: self.config.clone(),
+        }))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.config.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+}
+
+/// Quantized Llama loaded from a GGUF file (Q4_0, Q4_K, Q8_0, ...), the KV cache is held by the
+/// weights.
+#[derive(Clone)]
+pub struct QuantizedLlamaModel {
+    model: quantized_llama::ModelWeights,
+    vocab_size: usize,
+    context_length: usize,
+}
+
+impl QuantizedLlamaModel {
+    fn load(path: &std::path::Path, device: &Device) -> Result<Self, E> {
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file).map_err(|err| err.with_path(path))?;
+        let md_get = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .and_then(|v| v.to_u32().ok())
+                .map(|v| v as usize)
+        };
+        let vocab_size = match content.metadata.get("tokenizer.ggml.tokens") {
+            Some(tokens) => tokens.to_vec()?.len(),
+            None => anyhow::bail!("no tokenizer.ggml.tokens in {path:?}"),
+        };
+        let context_length = md_get("llama.context_length").unwrap_or(2048);
+        let model = quantized_llama::ModelWeights::from_gguf(content, &mut file, device)?;
+        Ok(Self {
+            model,
+            vocab_size,
+            context_length,
+        })
+    }
+}
+
+impl CausalLm for QuantizedLlamaModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        let seq_len = input.dim(1)?;
+        if index_pos > 0 && seq_len > 1 {
+            // The attention mask only covers several tokens fed from position 0.
+            let logits = self.forward_all(input, index_pos)?;
+            return Ok(logits.i((.., seq_len - 1, ..))?);
+        }
+        Ok(self.model.forward(input, index_pos)?)
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        // The quantized layers drop their KV cache whenever a forward starts at position 0.
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        // The quantized tensors are reference counted so this only copies the KV cache.
+        Ok(Box::new(self.clone()))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+}
+
+/// Llama loaded from a llama2.c checkpoint such as karpathy/tinyllamas `stories15M.bin`.
+pub struct Llama2cModel {
+    model: Arc<llama2_c::Llama>,
+    cache: llama2_c::Cache,
+    empty_cache: llama2_c::Cache,
+}
+
+/// Reads the header of a llama2.c checkpoint, refusing PyTorch checkpoints that were given a
+/// name other than `pytorch_model.bin`.
+pub(crate) fn read_llama2c_config(file: &mut std::fs::File) -> Result<llama2_c::Config, E> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    // A zip archive since PyTorch 1.6, a pickle starting with its magic number before
+    if magic == *b"PK\x03\x04" || magic == [0x80, 0x02, 0x8a, 0x0a] {
+        anyhow::bail!(PYTORCH_UNSUPPORTED);
+    }
+    Ok(llama2_c::Config::from_reader(file)?)
+}
+
+impl Llama2cModel {
+    fn load(path: &std::path::Path, device: &Device) -> Result<Self, E> {
+        let mut file = std::fs::File::open(path)?;
+        let config = read_llama2c_config(&mut file)?;
+        let weights =
+            llama2_c_weights::TransformerWeights::from_reader(&mut file, &config, device)?;
+        let vb = weights.var_builder(&config, device)?;
+        let cache = llama2_c::Cache::new(true, &config, vb.pp("rot"))?;
+        Ok(Self {
+            model: Arc::new(llama2_c::Llama::load(vb, config)?),
+            empty_cache: cache.clone(),
+            cache,
+        })
+    }
+}
+
+impl CausalLm for Llama2cModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        let logits = self.forward_all(input, index_pos)?;
+        Ok(logits.i((.., logits.dim(1)? - 1, ..))?)
+    }
+
+    fn forward_all(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        if index_pos > 0 && input.dim(1)? > 1 {
+            // The attention mask only covers several tokens fed from position 0.
+            let (model, cache) = (&self.model, &mut self.cache);
+            return forward_each(input, index_pos, |input, index_pos| {
+                Ok(model.forward(input, index_pos, cache)?.squeeze(1)?)
+            });
+        }
+        // llama2.c returns the logits for every position
+        Ok(self.model.forward(input, index_pos, &mut self.cache)?)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<(), E> {
+        // llama2.c caches `(batch, seq_len, heads, head_dim)` keys and values
+        for kv in self.cache.kvs.iter_mut() {
+            *kv = match kv.take() {
+                Some(_) if len == 0 => None,
+                Some((k, v)) if k.dim(1)? > len => {
+                    Some((k.narrow(1, 0, len)?, v.narrow(1, 0, len)?))
+                }
+                kv => kv,
+            };
+        }
+        Ok(())
+    }
+
+    fn can_truncate(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        self.cache = self.empty_cache.clone();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        Ok(Box::new(Self {
+            model: self.model.clone(),
+            cache: self.cache.clone(),
+            empty_cache: self.empty_cache.clone(),
+        }))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.model.config.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.model.config.seq_len
+    }
+}
+
+#[derive(Clone)]
+pub struct MistralModel {
+    model: mistral::Model,
+    info: ConfigInfo,
+}
+
+impl CausalLm for MistralModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        let logits = self.model.forward(input, index_pos)?;
+        Ok(logits.squeeze(1)?.to_dtype(DType::F32)?)
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        self.model.clear_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.info.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.info.context_length
+    }
+}
+
+#[derive(Clone)]
+pub struct PhiModel {
+    model: phi::Model,
+    info: ConfigInfo,
+}
+
+impl CausalLm for PhiModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        let seq_len = input.dim(1)?;
+        if index_pos > 0 && seq_len > 1 {
+            // The attention mask only covers several tokens fed from position 0.
+            let logits = self.forward_all(input, index_pos)?;
+            return Ok(logits.i((.., seq_len - 1, ..))?);
+        }
+        // Phi tracks the position through the length of its KV cache.
+        let logits = self.model.forward(input)?;
+        Ok(logits.to_dtype(DType::F32)?)
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        self.model.clear_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.info.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.info.context_length
+    }
+}
+
+#[derive(Clone)]
+pub struct Qwen2Model {
+    model: qwen2::Model,
+    info: ConfigInfo,
+}
+
+impl CausalLm for Qwen2Model {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        let logits = self.model.forward(input, index_pos)?;
+        Ok(logits.squeeze(1)?.to_dtype(DType::F32)?)
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        self.model.clear_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.info.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.info.context_length
+    }
+}
+
+#[derive(Clone)]
+pub struct GemmaModel {
+    model: gemma::Model,
+    info: ConfigInfo,
+}
+
+impl CausalLm for GemmaModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        let logits = self.model.forward(input, index_pos)?;
+        Ok(logits.squeeze(1)?.to_dtype(DType::F32)?)
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        self.model.clear_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.info.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.info.context_length
+    }
+}
+
+pub struct StableLmModel {
+    model: Arc<stable_lm::StableLm>,
+    cache: attention::Cache,
+    config: stable_lm::Config,
+}
+
+impl StableLmModel {
+    fn load(config: serde_json::Value, vb: VarBuilder) -> Result<Self, E> {
+        let config: stable_lm::Config = serde_json::from_value(config)?;
+        Ok(Self {
+            model: Arc::new(stable_lm::StableLm::load(vb, &config)?),
+            cache: attention::Cache::with_layers(config.num_hidden_layers),
+            config,
+        })
+    }
+}
+
+impl CausalLm for StableLmModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        Ok(self.model.forward(input, index_pos, &mut self.cache)?)
+    }
+
+    fn forward_all(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor, E> {
+        Ok(self.model.forward_all(input, index_pos, &mut self.cache)?)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<(), E> {
+        Ok(self.cache.truncate(len)?)
+    }
+
+    fn can_truncate(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        self.cache = attention::Cache::with_layers(self.config.num_hidden_layers);
+        Ok(())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CausalLm>, E> {
+        Ok(Box::new(Self {
+            model: self.model.clone(),
+            cache: self.cache.clone(),
+            config: self.config.clone(),
+        }))
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.config.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+}
@@ -0,0 +1,177 @@
+//! The rotary embeddings, causal attention and KV cache the Llama and StableLM models share. The
+//! cache can be truncated so that sessions can roll back the positions they fed.
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+
+/// The keys and values of every layer for the positions fed so far.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    kvs: Vec<Option<(Tensor, Tensor)>>,
+}
+
+impl Cache {
+    /// An empty cache for a model with `layers` layers.
+    pub fn with_layers(layers: usize) -> Self {
+        Self {
+            kvs: vec![None; layers],
+        }
+    }
+
+    /// The number of cached positions.
+    pub fn len(&self) -> usize {
+        match &self.kvs[0] {
+            Some((k, _)) => k.dims()[2],
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops the cached positions from `len` on.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.len() {
+            return Ok(());
+        }
+        for kv in self.kvs.iter_mut() {
+            *kv = match kv.take() {
+                Some((k, v)) if len > 0 => Some((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?)),
+                _ => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// The keys and values of each layer, in order.
+    pub(super) fn layers_mut(&mut self) -> impl Iterator<Item = &mut Option<(Tensor, Tensor)>> {
+        self.kvs.iter_mut()
+    }
+}
+
+/// Rotary embeddings for every position, computed once per model.
+#[derive(Debug, Clone)]
+pub(super) struct Rotary {
+    cos: Tensor,
+    sin: Tensor,
+}
+
+impl Rotary {
+    /// Embeddings rotating the first `dim` dimensions of each head.
+    pub(super) fn new(
+        dim: usize,
+        rope_theta: f64,
+        max_position_embeddings: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let theta: Vec<f32> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / rope_theta.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), device)?;
+        let positions = max_position_embeddings;
+        let freqs = Tensor::arange(0, positions as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((positions, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
+        Ok(Self {
+            cos: freqs.cos()?.to_dtype(dtype)?,
+            sin: freqs.sin()?.to_dtype(dtype)?,
+        })
+    }
+
+    /// Rotates the first dimensions of `x`, a `(batch, heads, seq_len, head_dim)` tensor starting
+    /// at `index_pos`, leaving the others as they are.
+    fn apply(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_, _, seq_len, head_dim) = x.dims4()?;
+        let dim = self.cos.dim(1)?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        let rotate = |x: &Tensor| {
+            let x1 = x.narrow(D::Minus1, 0, dim / 2)?;
+            let x2 = x.narrow(D::Minus1, dim / 2, dim / 2)?;
+            let rotated = Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)?;
+            x.broadcast_mul(&cos)? + rotated.broadcast_mul(&sin)?
+        };
+        if dim == head_dim {
+            return rotate(x);
+        }
+        let rot = rotate(&x.narrow(D::Minus1, 0, dim)?)?;
+        let pass = x.narrow(D::Minus1, dim, head_dim - dim)?;
+        Tensor::cat(&[&rot, &pass], D::Minus1)?.contiguous()
+    }
+}
+
+/// Multi-head attention with grouped key and value heads, over the linear layers `L`.
+#[derive(Debug, Clone)]
+pub(super) struct Attention<L> {
+    pub(super) q_proj: L,
+    pub(super) k_proj: L,
+    pub(super) v_proj: L,
+    pub(super) o_proj: L,
+    pub(super) num_heads: usize,
+    pub(super) num_kv_heads: usize,
+    pub(super) head_dim: usize,
+}
+
+impl<L: Module> Attention<L> {
+    /// Attends from `x`, a `(batch, seq_len, hidden_size)` tensor starting at `index_pos`, to the
+    /// positions before it, adding its keys and values to `kv`.
+    pub(super) fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        kv: &mut Option<(Tensor, Tensor)>,
+        rotary: &Rotary,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let shape = |heads| (b_sz, seq_len, heads, self.head_dim);
+        let q = self.q_proj.forward(x)?.reshape(shape(self.num_heads))?;
+        let k = self.k_proj.forward(x)?.reshape(shape(self.num_kv_heads))?;
+        let v = self.v_proj.forward(x)?.reshape(shape(self.num_kv_heads))?;
+        let q = rotary.apply(&q.transpose(1, 2)?.contiguous()?, index_pos)?;
+        let mut k = rotary.apply(&k.transpose(1, 2)?.contiguous()?, index_pos)?;
+        let mut v = v.transpose(1, 2)?.contiguous()?;
+
+        if let Some((cache_k, cache_v)) = kv.as_ref() {
+            k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
+            v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
+        }
+        *kv = Some((k.clone(), v.clone()));
+
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = candle_transformers::utils::repeat_kv(k, n_rep)?;
+        let v = candle_transformers::utils::repeat_kv(v, n_rep)?;
+
+        let in_dtype = q.dtype();
+        let q = q.to_dtype(DType::F32)?;
+        let k = k.to_dtype(DType::F32)?;
+        let v = v.to_dtype(DType::F32)?;
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let att = if seq_len == 1 {
+            att
+        } else {
+            causal_mask(&att, seq_len)?
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let y = att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?;
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, hidden_size))?;
+        self.o_proj.forward(&y)
+    }
+}
+
+/// Masks the attention scores `att` of the last `seq_len` positions so that each new position
+/// attends to the whole cache and to the new positions before it.
+fn causal_mask(att: &Tensor, seq_len: usize) -> Result<Tensor> {
+    let kv_len = att.dim(D::Minus1)?;
+    let offset = kv_len - seq_len;
+    let mask: Vec<u8> = (0..seq_len)
+        .flat_map(|i| (0..kv_len).map(move |j| u8::from(j > i + offset)))
+        .collect();
+    let mask =
+        Tensor::from_slice(&mask, (seq_len, kv_len), att.device())?.broadcast_as(att.shape())?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, att.device())?.broadcast_as(att.shape())?;
+    mask.where_cond(&neg_inf, att)
+}
@@ -0,0 +1,227 @@
+//! Llama with a KV cache that can be rolled back and logits for every position, which the
+//! candle implementation does not expose. Loads the same safetensors weights.
+// https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/llama.rs
+
+use candle_core::{DType, IndexOp, Module, Result, Tensor};
+use candle_nn::{embedding, linear_no_bias, rms_norm, Embedding, Linear, RmsNorm, VarBuilder};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use super::attention::{self, Cache, Rotary};
+use crate::lora::{LoraAdapter, LoraLinear};
+
+fn default_rms_norm_eps() -> f64 {
+    1e-6
+}
+
+fn default_rope_theta() -> f32 {
+    10_000.0
+}
+
+fn default_max_position_embeddings() -> usize {
+    4096
+}
+
+/// The fields of a Hugging Face Llama `config.json`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    #[serde(default = "default_rms_norm_eps")]
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+}
+
+impl Config {
+    fn num_key_value_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+}
+
+fn linear(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<LoraLinear> {
+    Ok(LoraLinear::new(linear_no_bias(in_dim, out_dim, vb)?))
+}
+
+type Attention = attention::Attention<LoraLinear>;
+
+fn load_attention(vb: VarBuilder, config: &Config) -> Result<Attention> {
+    let head_dim = config.head_dim();
+    let size_q = head_dim * config.num_attention_heads;
+    let size_kv = head_dim * config.num_key_value_heads();
+    Ok(Attention {
+        q_proj: linear(config.hidden_size, size_q, vb.pp("q_proj"))?,
+        k_proj: linear(config.hidden_size, size_kv, vb.pp("k_proj"))?,
+        v_proj: linear(config.hidden_size, size_kv, vb.pp("v_proj"))?,
+        o_proj: linear(size_q, config.hidden_size, vb.pp("o_proj"))?,
+        num_heads: config.num_attention_heads,
+        num_kv_heads: config.num_key_value_heads(),
+        head_dim,
+    })
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: LoraLinear,
+    up_proj: LoraLinear,
+    down_proj: LoraLinear,
+}
+
+impl Mlp {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (h_size, i_size) = (config.hidden_size, config.intermediate_size);
+        Ok(Self {
+            gate_proj: linear(h_size, i_size, vb.pp("gate_proj"))?,
+            up_proj: linear(h_size, i_size, vb.pp("up_proj"))?,
+            down_proj: linear(i_size, h_size, vb.pp("down_proj"))?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x = (candle_nn::ops::silu(&self.gate_proj.forward(x)?)? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    input_layernorm: RmsNorm,
+    attn: Attention,
+    post_attention_layernorm: RmsNorm,
+    mlp: Mlp,
+}
+
+impl Block {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (size, eps) = (config.hidden_size, config.rms_norm_eps);
+        Ok(Self {
+            input_layernorm: rms_norm(size, eps, vb.pp("input_layernorm"))?,
+            attn: load_attention(vb.pp("self_attn"), config)?,
+            post_attention_layernorm: rms_norm(size, eps, vb.pp("post_attention_layernorm"))?,
+            mlp: Mlp::load(vb.pp("mlp"), config)?,
+        })
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        kv: &mut Option<(Tensor, Tensor)>,
+        rotary: &Rotary,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.input_layernorm.forward(x)?;
+        let x = (self.attn.forward(&x, index_pos, kv, rotary)? + residual)?;
+        let residual = &x;
+        let x = self
+            .mlp
+            .forward(&self.post_attention_layernorm.forward(&x)?)?;
+        x + residual
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Llama {
+    embed_tokens: Embedding,
+    blocks: Vec<Block>,
+    norm: RmsNorm,
+    lm_head: LoraLinear,
+    rotary: Rotary,
+}
+
+impl Llama {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let embed_tokens = embedding(
+            config.vocab_size,
+            config.hidden_size,
+            vb.pp("model.embed_tokens"),
+        )?;
+        let lm_head = if config.tie_word_embeddings && !vb.contains_tensor("lm_head.weight") {
+            LoraLinear::new(Linear::new(embed_tokens.embeddings().clone(), None))
+        } else {
+            linear(config.hidden_size, config.vocab_size, vb.pp("lm_head"))?
+        };
+        let blocks = (0..config.num_hidden_layers)
+            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), config))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            embed_tokens,
+            blocks,
+            norm: rms_norm(config.hidden_size, config.rms_norm_eps, vb.pp("model.norm"))?,
+            lm_head,
+            rotary: Rotary::new(
+                config.head_dim(),
+                config.rope_theta as f64,
+                config.max_position_embeddings,
+                vb.dtype(),
+                vb.device(),
+            )?,
+        })
+    }
+
+    /// A copy of the model with `adapter` applied to its linear layers, sharing every weight the
+    /// adapter does not change. See [`LoraLinear::with_adapter`] for `merge`.
+    pub fn with_lora(&self, adapter: &LoraAdapter, merge: bool) -> Result<Self> {
+        let mut model = self.clone();
+        let mut adapted = HashSet::from(["lm_head".to_string()]);
+        model.lm_head = model.lm_head.with_adapter(adapter, "lm_head", merge)?;
+        for (i, block) in model.blocks.iter_mut().enumerate() {
+            let layers = [
+                (&mut block.attn.q_proj, "self_attn.q_proj"),
+                (&mut block.attn.k_proj, "self_attn.k_proj"),
+                (&mut block.attn.v_proj, "self_attn.v_proj"),
+                (&mut block.attn.o_proj, "self_attn.o_proj"),
+                (&mut block.mlp.gate_proj, "mlp.gate_proj"),
+                (&mut block.mlp.up_proj, "mlp.up_proj"),
+                (&mut block.mlp.down_proj, "mlp.down_proj"),
+            ];
+            for (layer, name) in layers {
+                let path = format!("model.layers.{i}.{name}");
+                *layer = layer.with_adapter(adapter, &path, merge)?;
+                adapted.insert(path);
+            }
+        }
+        if let Some(layer) = adapter.layers().find(|layer| !adapted.contains(*layer)) {
+            candle_core::bail!("the adapter targets {layer}, which is not a linear layer of Llama")
+        }
+        Ok(model)
+    }
+
+    fn hidden(&self, input: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let mut x = self.embed_tokens.forward(input)?;
+        for (block, kv) in self.blocks.iter().zip(cache.layers_mut()) {
+            x = block.forward(&x, index_pos, kv, &self.rotary)?;
+        }
+        self.norm.forward(&x)
+    }
+
+    /// The `(batch, vocab_size)` f32 logits for the last position of `input`.
+    pub fn forward(&self, input: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let x = self.hidden(input, index_pos, cache)?;
+        let x = x.i((.., x.dim(1)? - 1, ..))?.contiguous()?;
+        self.lm_head.forward(&x)?.to_dtype(DType::F32)
+    }
+
+    /// The `(batch, seq_len, vocab_size)` f32 logits for every position of `input`.
+    pub fn forward_all(
+        &self,
+        input: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let x = self.hidden(input, index_pos, cache)?;
+        self.lm_head.forward(&x)?.to_dtype(DType::F32)
+    }
+}
@@ -0,0 +1,189 @@
+//! StableLM with the KV cache kept apart from the weights, so that sessions share the weights and
+//! can roll their cache back, which the candle implementation does not allow. Loads the same
+//! safetensors weights.
+// https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/stable_lm.rs
+
+use candle_core::{DType, IndexOp, Module, Result, Tensor};
+use candle_nn::{
+    embedding, layer_norm, linear, linear_no_bias, Activation, Embedding, LayerNorm, Linear,
+    VarBuilder,
+};
+use serde::Deserialize;
+
+use super::attention::{self, Cache, Rotary};
+
+/// The fields of a Hugging Face StableLM `config.json`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub intermediate_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub hidden_act: Activation,
+    /// The share of each head's dimensions the rotary embeddings apply to.
+    pub partial_rotary_factor: f64,
+    pub rope_theta: f64,
+    pub max_position_embeddings: usize,
+    pub layer_norm_eps: f64,
+    /// Used by StableLM 2.
+    #[serde(default)]
+    pub use_qkv_bias: bool,
+}
+
+impl Config {
+    fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    fn rotary_dim(&self) -> usize {
+        (self.head_dim() as f64 * self.partial_rotary_factor) as usize
+    }
+}
+
+type Attention = attention::Attention<Linear>;
+
+fn load_attention(vb: VarBuilder, config: &Config) -> Result<Attention> {
+    let head_dim = config.head_dim();
+    let size_q = head_dim * config.num_attention_heads;
+    let size_kv = head_dim * config.num_key_value_heads;
+    let qkv = if config.use_qkv_bias {
+        linear
+    } else {
+        linear_no_bias
+    };
+    Ok(Attention {
+        q_proj: qkv(config.hidden_size, size_q, vb.pp("q_proj"))?,
+        k_proj: qkv(config.hidden_size, size_kv, vb.pp("k_proj"))?,
+        v_proj: qkv(config.hidden_size, size_kv, vb.pp("v_proj"))?,
+        o_proj: linear_no_bias(size_q, config.hidden_size, vb.pp("o_proj"))?,
+        num_heads: config.num_attention_heads,
+        num_kv_heads: config.num_key_value_heads,
+        head_dim,
+    })
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act: Activation,
+}
+
+impl Mlp {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (h_size, i_size) = (config.hidden_size, config.intermediate_size);
+        Ok(Self {
+            gate_proj: linear_no_bias(h_size, i_size, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(h_size, i_size, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(i_size, h_size, vb.pp("down_proj"))?,
+            act: config.hidden_act,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x = (self.act.forward(&self.gate_proj.forward(x)?)? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    input_layernorm: LayerNorm,
+    attn: Attention,
+    post_attention_layernorm: LayerNorm,
+    mlp: Mlp,
+}
+
+impl Block {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (size, eps) = (config.hidden_size, config.layer_norm_eps);
+        Ok(Self {
+            input_layernorm: layer_norm(size, eps, vb.pp("input_layernorm"))?,
+            attn: load_attention(vb.pp("self_attn"), config)?,
+            post_attention_layernorm: layer_norm(size, eps, vb.pp("post_attention_layernorm"))?,
+            mlp: Mlp::load(vb.pp("mlp"), config)?,
+        })
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        kv: &mut Option<(Tensor, Tensor)>,
+        rotary: &Rotary,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.input_layernorm.forward(x)?;
+        let x = (self.attn.forward(&x, index_pos, kv, rotary)? + residual)?;
+        let residual = &x;
+        let x = self
+            .mlp
+            .forward(&self.post_attention_layernorm.forward(&x)?)?;
+        x + residual
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StableLm {
+    embed_tokens: Embedding,
+    blocks: Vec<Block>,
+    norm: LayerNorm,
+    lm_head: Linear,
+    rotary: Rotary,
+}
+
+impl StableLm {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let embed_tokens = embedding(
+            config.vocab_size,
+            config.hidden_size,
+            vb.pp("model.embed_tokens"),
+        )?;
+        let blocks = (0..config.num_hidden_layers)
+            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), config))
+            .collect::<Result<Vec<_>>>()?;
+        let (size, eps) = (config.hidden_size, config.layer_norm_eps);
+        Ok(Self {
+            embed_tokens,
+            blocks,
+            norm: layer_norm(size, eps, vb.pp("model.norm"))?,
+            lm_head: linear_no_bias(size, config.vocab_size, vb.pp("lm_head"))?,
+            rotary: Rotary::new(
+                config.rotary_dim(),
+                config.rope_theta,
+                config.max_position_embeddings,
+                vb.dtype(),
+                vb.device(),
+            )?,
+        })
+    }
+
+    fn hidden(&self, input: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let mut x = self.embed_tokens.forward(input)?;
+        for (block, kv) in self.blocks.iter().zip(cache.layers_mut()) {
+            x = block.forward(&x, index_pos, kv, &self.rotary)?;
+        }
+        self.norm.forward(&x)
+    }
+
+    /// The `(batch, vocab_size)` f32 logits for the last position of `input`.
+    pub fn forward(&self, input: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let x = self.hidden(input, index_pos, cache)?;
+        let x = x.i((.., x.dim(1)? - 1, ..))?.contiguous()?;
+        self.lm_head.forward(&x)?.to_dtype(DType::F32)
+    }
+
+    /// The `(batch, seq_len, vocab_size)` f32 logits for every position of `input`.
+    pub fn forward_all(
+        &self,
+        input: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let x = self.hidden(input, index_pos, cache)?;
+        self.lm_head.forward(&x)?.to_dtype(DType::F32)
+    }
+}
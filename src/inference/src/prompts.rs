@@ -0,0 +1,514 @@
+use anyhow::{Error as E, Result};
+use minijinja::{Environment, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::generation::{Generation, GenerationConfig};
+use crate::session::Session;
+use crate::text_generation::TextGeneration;
+use crate::tools::{self, Tool, ToolCall};
+
+/// A single message of a conversation, `role` is usually one of "system", "user" or "assistant".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new("system", content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new("assistant", content)
+    }
+}
+
+/// The chat formats that come with the crate, for models whose tokenizer config has no
+/// `chat_template`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatFormat {
+    ChatMl,
+    Llama2,
+    Llama3,
+    Zephyr,
+    Alpaca,
+}
+
+impl ChatFormat {
+    /// Parses the name of a format, e.g. `chatml` or `llama-3`, ignoring case and separators.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        match name.as_str() {
+            "chatml" => Some(Self::ChatMl),
+            "llama2" => Some(Self::Llama2),
+            "llama3" => Some(Self::Llama3),
+            "zephyr" => Some(Self::Zephyr),
+            "alpaca" => Some(Self::Alpaca),
+            _ => None,
+        }
+    }
+
+    pub fn template(&self) -> ChatTemplate {
+        let (source, bos_token, eos_token) = match self {
+            Self::ChatMl => (CHATML, "", "<|im_end|>"),
+            Self::Llama2 => (LLAMA2, "<s>", "</s>"),
+            Self::Llama3 => (LLAMA3, "<|begin_of_text|>", "<|eot_id|>"),
+            Self::Zephyr => (ZEPHYR, "<s>", "</s>"),
+            Self::Alpaca => (ALPACA, "<s>", "</s>"),
+        };
+        ChatTemplate::new(source, bos_token, eos_token).expect("built-in templates are valid")
+    }
+}
+
+/// Turns a conversation into the prompt text a chat model was trained on, rendering a Jinja
+/// template the way Hugging Face `transformers` does.
+#[derive(Debug, Clone)]
+pub struct ChatTemplate {
+    source: String,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    /// A template from its Jinja `source`, which can refer to `messages`,
+    /// `add_generation_prompt`, `bos_token` and `eos_token`. Sources that never refer to
+    /// `messages` are refused, as they cannot be chat templates.
+    pub fn new(
+        source: impl Into<String>,
+        bos_token: impl Into<String>,
+        eos_token: impl Into<String>,
+    ) -> Result<Self, E> {
+        let template = Self {
+            source: source.into(),
+            bos_token: bos_token.into(),
+            eos_token: eos_token.into(),
+        };
+        let env = environment();
+        let parsed = env.template_from_str(&template.source)?;
+        if !parsed.undeclared_variables(false).contains("messages") {
+            anyhow::bail!("a chat template has to go through `messages`");
+        }
+        Ok(template)
+    }
+
+    /// Reads the `chat_template` of a `tokenizer_config.json` along with its special tokens,
+    /// `None` when the config has no template.
+    pub fn from_tokenizer_config(path: &Path) -> Result<Option<Self>, E> {
+        let json: serde_json::Value = serde_json::from_reader(std::fs::File::open(path)?)?;
+        let source = match json.get("chat_template") {
+            Some(serde_json::Value::String(source)) => source.as_str(),
+            // Several named templates, of which the default one is used for chatting
+            Some(serde_json::Value::Array(templates)) => {
+                let default = templates.iter().find(|template| {
+                    template.get("name").and_then(|v| v.as_str()) == Some("default")
+                });
+                match default.and_then(|template| template.get("template")?.as_str()) {
+                    Some(source) => source,
+                    None => anyhow::bail!("no default chat template in {path:?}"),
+                }
+            }
+            _ => return Ok(None),
+        };
+        // Either the token itself or an added token object with its `content`
+        let token = |key: &str| match json.get(key) {
+            Some(serde_json::Value::Object(token)) => token.get("content").and_then(|c| c.as_str()),
+            Some(token) => token.as_str(),
+            None => None,
+        };
+        let bos_token = token("bos_token").unwrap_or_default();
+        let eos_token = token("eos_token").unwrap_or_default();
+        Ok(Some(Self::new(source, bos_token, eos_token)?))
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn bos_token(&self) -> &str {
+        &self.bos_token
+    }
+
+    pub fn eos_token(&self) -> &str {
+        &self.eos_token
+    }
+
+    /// Renders `messages`, followed by the start of an assistant message when
+    /// `add_generation_prompt` is set so that the model replies to the conversation.
+    pub fn render(
+        &self,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+    ) -> Result<String, E> {
+        let context = minijinja::context! {
+            messages => messages,
+            add_generation_prompt => add_generation_prompt,
+            bos_token => &self.bos_token,
+            eos_token => &self.eos_token,
+        };
+        Ok(environment().render_str(&self.source, context)?)
+    }
+}
+
+/// An environment matching the one `transformers` renders chat templates in.
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    // Templates call Python string methods such as `strip` and `title`
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function("raise_exception", |message: String| -> Result<String, _> {
+        Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+    });
+    env
+}
+
+/// What a [`Conversation`] does with its oldest turns once it goes over its token budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistoryOverflow {
+    /// Forget the oldest turns.
+    #[default]
+    Drop,
+    /// Let the model summarize the oldest turns in up to `max_tokens` tokens, the summary is
+    /// kept in the system message in their place.
+    Summarize { max_tokens: usize },
+}
+
+/// The history of a chat, e.g. with an NPC, kept within a token budget so that it does not
+/// outgrow the model's context window.
+///
+/// The system prompt and the pinned facts are always kept, old turns are dropped or summarized
+/// as configured by `overflow` when [`Conversation::fit`] finds the prompt over `token_budget`.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    /// The maximum number of tokens of the formatted prompt, which should leave room in the
+    /// context window for the reply.
+    pub token_budget: usize,
+    pub overflow: HistoryOverflow,
+    system: Option<String>,
+    pinned: Vec<String>,
+    summary: Option<String>,
+    turns: Vec<ChatMessage>,
+    tools: Vec<Tool>,
+}
+
+impl Conversation {
+    pub fn new(system: Option<String>, token_budget: usize, overflow: HistoryOverflow) -> Self {
+        Self {
+            token_budget,
+            overflow,
+            system,
+            pinned: Vec::new(),
+            summary: None,
+            turns: Vec::new(),
+            tools: Vec::new(),
+        }
+    }
+
+    pub fn set_system(&mut self, system: Option<String>) {
+        self.system = system;
+    }
+
+    /// Adds a fact that is never forgotten, e.g. the player's name or a promise the NPC made.
+    pub fn pin(&mut self, fact: impl Into<String>) {
+        self.pinned.push(fact.into());
+    }
+
+    /// Removes a pinned fact, returning whether it was pinned.
+    pub fn unpin(&mut self, fact: &str) -> bool {
+        let len = self.pinned.len();
+        self.pinned.retain(|pinned| pinned != fact);
+        self.pinned.len() != len
+    }
+
+    pub fn pinned(&self) -> &[String] {
+        &self.pinned
+    }
+
+    /// Sets the tools the model can call, which are described in the system message.
+    pub fn set_tools(&mut self, tools: Vec<Tool>) {
+        self.tools = tools;
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    /// The summary of the turns that were summarized away, if any.
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// The turns of the conversation that were not forgotten, oldest first.
+    pub fn turns(&self) -> &[ChatMessage] {
+        &self.turns
+    }
+
+    pub fn push(&mut self, message: ChatMessage) {
+        self.turns.push(message);
+    }
+
+    /// Forgets every turn and the summary, keeping the system prompt and pinned facts.
+    pub fn clear(&mut self) {
+        self.turns.clear();
+        self.summary = None;
+    }
+
+    /// The conversation as messages for a chat template, starting with a system message that
+    /// holds the system prompt, the tools, the pinned facts and the summary.
+    pub fn messages(&self) -> Vec<ChatMessage> {
+        let mut system: Vec<String> = self.system.iter().cloned().collect();
+        if !self.tools.is_empty() {
+            system.push(tools::tools_prompt(&self.tools));
+        }
+        if !self.pinned.is_empty() {
+            let facts: Vec<String> = self.pinned.iter().map(|fact| format!("- {fact}")).collect();
+            system.push(format!("Facts to remember:\n{}", facts.join("\n")));
+        }
+        if let Some(summary) = &self.summary {
+            system.push(format!("Summary of the conversation so far:\n{summary}"));
+        }
+        let mut messages = Vec::with_capacity(self.turns.len() + 1);
+        if !system.is_empty() {
+            messages.push(ChatMessage::system(system.join("\n\n")));
+        }
+        messages.extend(self.turns.iter().cloned());
+        messages
+    }
+
+    /// The number of tokens of the prompt asking `pipeline` to reply to the conversation.
+    pub fn token_count(&self, pipeline: &TextGeneration) -> Result<usize, E> {
+        Ok(pipeline.encode_chat(&self.messages(), true)?.len())
+    }
+
+    /// Adds `message` from the user and starts generating the reply in `session`, which should be
+    /// added with [`Conversation::push_reply`] once complete. Keep using the same session for the
+    /// conversation so that the turns already in its KV cache are not run through the model again.
+    /// The message is taken back when the reply cannot be started, but stays when the generation
+    /// fails later on, so keep a clone of the conversation to go back to in that case.
+    pub fn reply<'a>(
+        &mut self,
+        pipeline: &'a TextGeneration,
+        session: &'a mut Session,
+        message: impl Into<String>,
+        config: GenerationConfig,
+    ) -> Result<Generation<'a>, E> {
+        self.push(ChatMessage::user(message));
+        let generation = self.generate(pipeline, session, config);
+        if generation.is_err() {
+            // Leave the conversation as it was so that the message can be sent again
+            self.turns.pop();
+        }
+        generation
+    }
+
+    /// Starts generating the next reply of the assistant, e.g. after a tool result was added.
+    pub fn generate<'a>(
+        &mut self,
+        pipeline: &'a TextGeneration,
+        session: &'a mut Session,
+        config: GenerationConfig,
+    ) -> Result<Generation<'a>, E> {
+        self.fit(pipeline)?;
+        let tokens = pipeline.encode_chat(&self.messages(), true)?;
+        session.generate_tokens(pipeline, &tokens, config)
+    }
+
+    /// Adds a reply of the assistant. When the reply calls one of the tools, `dispatch` runs the
+    /// call and its result, or error, is added for the model to see on its next reply. Returns
+    /// the call so that the caller knows to [`Conversation::generate`] again.
+    pub fn push_reply(
+        &mut self,
+        reply: impl Into<String>,
+        dispatch: impl FnOnce(&ToolCall) -> Result<serde_json::Value, E>,
+    ) -> Option<ToolCall> {
+        let reply = reply.into();
+        let call = ToolCall::parse(&reply);
+        self.push(ChatMessage::assistant(reply));
+        let call = call?;
+        let result = if self.tools.iter().any(|tool| tool.name == call.name) {
+            dispatch(&call)
+        } else {
+            Err(anyhow::anyhow!("there is no tool named {}", call.name))
+        };
+        self.push(call.response(result));
+        Some(call)
+    }
+
+    /// Drops or summarizes the oldest turns until the prompt fits in the token budget, returning
+    /// the number of turns let go. Fails when the system prompt, pinned facts and last turn do
+    /// not fit on their own, leaving the conversation untouched.
+    pub fn fit(&mut self, pipeline: &TextGeneration) -> Result<usize, E> {
+        self.fit_with(
+            |messages| Ok(pipeline.encode_chat(messages, true)?.len()),
+            |messages, max_tokens| summarize(pipeline, messages, max_tokens),
+        )
+    }
+
+    /// [`Conversation::fit`] with the tokens counted by `count` and the summaries written by
+    /// `summarize`.
+    pub(crate) fn fit_with(
+        &mut self,
+        count: impl Fn(&[ChatMessage]) -> Result<usize, E>,
+        summarize: impl FnOnce(&[ChatMessage], usize) -> Result<String, E>,
+    ) -> Result<usize, E> {
+        if count(&self.messages())? <= self.token_budget {
+            return Ok(0);
+        }
+        // Work on a copy so that a conversation that cannot fit keeps its history
+        let mut fitted = self.clone();
+        let previous = match self.overflow {
+            HistoryOverflow::Drop => None,
+            // An empty summary counts the tokens of its header, leaving room for the summary
+            HistoryOverflow::Summarize { .. } => fitted.summary.replace(String::new()),
+        };
+        let target = match self.overflow {
+            HistoryOverflow::Drop => self.token_budget,
+            HistoryOverflow::Summarize { max_tokens } => {
+                self.token_budget.saturating_sub(max_tokens)
+            }
+        };
+        let mut dropped = Vec::new();
+        while fitted.turns.len() > 1 && count(&fitted.messages())? > target {
+            dropped.push(fitted.turns.remove(0));
+            // Keep the turns starting with the user as templates such as Llama-2 require
+            while fitted.turns.len() > 1 && fitted.turns[0].role != "user" {
+                dropped.push(fitted.turns.remove(0));
+            }
+        }
+        let forgotten = dropped.len();
+        if let HistoryOverflow::Summarize { max_tokens } = self.overflow {
+            fitted.summary = if dropped.is_empty() {
+                previous
+            } else {
+                // The previous summary is folded into the new one
+                if let Some(summary) = previous {
+                    dropped.insert(0, ChatMessage::system(summary));
+                }
+                let summary = summarize(&dropped, max_tokens)?;
+                Some(summary).filter(|summary| !summary.is_empty())
+            };
+        }
+        if count(&fitted.messages())? > self.token_budget {
+            anyhow::bail!(
+                "the conversation does not fit in {} tokens",
+                self.token_budget
+            );
+        }
+        *self = fitted;
+        Ok(forgotten)
+    }
+}
+
+/// Lets the model summarize `messages` in up to `max_tokens` tokens.
+fn summarize(
+    pipeline: &TextGeneration,
+    messages: &[ChatMessage],
+    max_tokens: usize,
+) -> Result<String, E> {
+    let transcript: Vec<String> = messages
+        .iter()
+        .map(|message| format!("{}: {}", message.role, message.content))
+        .collect();
+    let request = [
+        ChatMessage::system(
+            "Summarize the following conversation in a few sentences, keeping every detail \
+             needed to continue it.",
+        ),
+        ChatMessage::user(transcript.join("\n")),
+    ];
+    let config = GenerationConfig {
+        max_new_tokens: max_tokens,
+        ..Default::default()
+    };
+    let (summary, _) = pipeline.generate_chat(&request, config)?.complete()?;
+    Ok(summary.trim().to_string())
+}
+
+const CHATML: &str = concat!(
+    "{% for message in messages %}",
+    "{{ '<|im_start|>' + message.role + '\\n' + message.content + '<|im_end|>\\n' }}",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}",
+);
+
+// https://huggingface.co/meta-llama/Llama-2-7b-chat-hf/blob/main/tokenizer_config.json
+const LLAMA2: &str = concat!(
+    "{% if messages[0].role == 'system' %}",
+    "{% set loop_messages = messages[1:] %}{% set system_message = messages[0].content %}",
+    "{% else %}",
+    "{% set loop_messages = messages %}{% set system_message = false %}",
+    "{% endif %}",
+    "{% for message in loop_messages %}",
+    "{% if (message.role == 'user') != (loop.index0 % 2 == 0) %}",
+    "{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}",
+    "{% endif %}",
+    "{% if loop.index0 == 0 and system_message != false %}",
+    "{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message.content %}",
+    "{% else %}",
+    "{% set content = message.content %}",
+    "{% endif %}",
+    "{% if message.role == 'user' %}",
+    "{{ bos_token + '[INST] ' + content | trim + ' [/INST]' }}",
+    "{% elif message.role == 'assistant' %}",
+    "{{ ' ' + content | trim + ' ' + eos_token }}",
+    "{% endif %}",
+    "{% endfor %}",
+);
+
+// https://huggingface.co/meta-llama/Meta-Llama-3-8B-Instruct/blob/main/tokenizer_config.json
+const LLAMA3: &str = concat!(
+    "{% for message in messages %}",
+    "{% set content = '<|start_header_id|>' + message.role + '<|end_header_id|>\\n\\n' ",
+    "+ message.content | trim + '<|eot_id|>' %}",
+    "{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}",
+    "{{ content }}",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}",
+    "{{ '<|start_header_id|>assistant<|end_header_id|>\\n\\n' }}",
+    "{% endif %}",
+);
+
+// https://huggingface.co/HuggingFaceH4/zephyr-7b-beta/blob/main/tokenizer_config.json
+const ZEPHYR: &str = concat!(
+    "{% for message in messages %}",
+    "{{ '<|' + message.role + '|>\\n' + message.content + eos_token + '\\n' }}",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}{{ '<|assistant|>\\n' }}{% endif %}",
+);
+
+// https://github.com/tatsu-lab/stanford_alpaca#data-release
+const ALPACA: &str = concat!(
+    "{% if messages[0].role == 'system' %}",
+    "{{ messages[0].content + '\\n\\n' }}",
+    "{% set loop_messages = messages[1:] %}",
+    "{% else %}",
+    "{{ 'Below is an instruction that describes a task. ",
+    "Write a response that appropriately completes the request.\\n\\n' }}",
+    "{% set loop_messages = messages %}",
+    "{% endif %}",
+    "{% for message in loop_messages %}",
+    "{% if message.role == 'user' %}",
+    "{{ '### Instruction:\\n' + message.content + '\\n\\n' }}",
+    "{% elif message.role == 'assistant' %}",
+    "{{ '### Response:\\n' + message.content + eos_token + '\\n\\n' }}",
+    "{% endif %}",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}{{ '### Response:\\n' }}{% endif %}",
+);
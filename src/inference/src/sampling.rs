@@ -0,0 +1,206 @@
+use anyhow::{Error as E, Result};
+use candle_core::{DType, Tensor};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// How the next token is picked from the logits. Filters that are `None` are not applied.
+#[derive(Debug, Clone)]
+pub struct SamplingConfig {
+    pub seed: u64,
+    /// Always pick the most likely token, ignoring every other setting.
+    pub greedy: bool,
+    /// Values at or below zero behave like `greedy`.
+    pub temperature: f64,
+    /// Only sample from the `top_k` most likely tokens.
+    pub top_k: Option<usize>,
+    /// Only sample from the smallest set of tokens whose probabilities add up to `top_p`.
+    pub top_p: Option<f64>,
+    /// Only sample from tokens at least `min_p` times as likely as the most likely token.
+    pub min_p: Option<f64>,
+    /// Locally typical sampling, keeps the tokens closest to the expected information content
+    /// until their probabilities add up to `typical_p`.
+    pub typical_p: Option<f64>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            seed: 299792458,
+            greedy: true,
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            typical_p: None,
+        }
+    }
+}
+
+impl SamplingConfig {
+    /// Sampling at `temperature` with no filters.
+    pub fn with_temperature(temperature: f64) -> Self {
+        Self {
+            greedy: false,
+            temperature,
+            ..Default::default()
+        }
+    }
+
+    fn is_greedy(&self) -> bool {
+        self.greedy || self.temperature <= 1e-7
+    }
+}
+
+/// Picks tokens from logits according to a [`SamplingConfig`], holding the random state of a
+/// single generation.
+pub struct Sampler {
+    config: SamplingConfig,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(config: SamplingConfig) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        Self { config, rng }
+    }
+
+    pub fn config(&self) -> &SamplingConfig {
+        &self.config
+    }
+
+    /// Samples a token from a `(vocab_size,)` tensor of logits.
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32, E> {
+        let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        self.sample_from(&logits)
+    }
+
+    pub fn sample_from(&mut self, logits: &[f32]) -> Result<u32, E> {
+        if self.config.is_greedy() {
+            return argmax(logits);
+        }
+        let candidates = self.candidates(logits);
+        if candidates.is_empty() {
+            anyhow::bail!("no token left to sample from");
+        }
+        let distribution = WeightedIndex::new(candidates.iter().map(|(_, p)| *p))?;
+        Ok(candidates[distribution.sample(&mut self.rng)].0)
+    }
+
+    /// The tokens left after applying the temperature and filters, with their unnormalized
+    /// probabilities sorted from most to least likely.
+    fn candidates(&self, logits: &[f32]) -> Vec<(u32, f32)> {
+        let temperature = self.config.temperature as f32;
+        let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut candidates: Vec<(u32, f32)> = logits
+            .iter()
+            .enumerate()
+            .map(|(token, logit)| (token as u32, ((logit - max_logit) / temperature).exp()))
+            .filter(|(_, p)| *p > 0.0 && p.is_finite())
+            .collect();
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        normalize(&mut candidates);
+
+        if let Some(top_k) = self.config.top_k {
+            candidates.truncate(top_k.max(1));
+            normalize(&mut candidates);
+        }
+        if let Some(typical_p) = self.config.typical_p {
+            typical_filter(&mut candidates, typical_p as f32);
+            normalize(&mut candidates);
+        }
+        if let Some(top_p) = self.config.top_p {
+            top_p_filter(&mut candidates, top_p as f32);
+            normalize(&mut candidates);
+        }
+        if let Some(min_p) = self.config.min_p {
+            if let Some(&(_, max_p)) = candidates.first() {
+                let threshold = max_p * min_p as f32;
+                candidates.retain(|(_, p)| *p >= threshold);
+            }
+        }
+        candidates
+    }
+}
+
+/// The log-probability of a generated token and of the most likely tokens at its position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprobs {
+    pub token: u32,
+    pub logprob: f32,
+    /// The most likely tokens with their log-probabilities, most likely first.
+    pub top: Vec<(u32, f32)>,
+}
+
+impl TokenLogprobs {
+    /// Computes the log-probabilities of `token` and of the `top_n` most likely tokens from
+    /// `logits`, before the temperature and filters of the sampling are applied.
+    pub fn new(logits: &[f32], token: u32, top_n: usize) -> Self {
+        let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = logits.iter().map(|logit| (logit - max_logit).exp()).sum();
+        let log_sum = max_logit + sum.ln();
+        let mut top: Vec<(u32, f32)> = logits
+            .iter()
+            .enumerate()
+            .map(|(token, logit)| (token as u32, logit - log_sum))
+            .filter(|(_, logprob)| logprob.is_finite())
+            .collect();
+        if top_n < top.len() {
+            top.select_nth_unstable_by(top_n, |(_, a), (_, b)| b.total_cmp(a));
+            top.truncate(top_n);
+        }
+        top.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        Self {
+            token,
+            logprob: logits[token as usize] - log_sum,
+            top,
+        }
+    }
+}
+
+fn argmax(logits: &[f32]) -> Result<u32, E> {
+    match logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    {
+        Some((token, _)) => Ok(token as u32),
+        None => anyhow::bail!("cannot sample from empty logits"),
+    }
+}
+
+fn normalize(candidates: &mut [(u32, f32)]) {
+    let sum: f32 = candidates.iter().map(|(_, p)| p).sum();
+    if sum > 0.0 {
+        candidates.iter_mut().for_each(|(_, p)| *p /= sum);
+    }
+}
+
+/// Keeps the most likely tokens until their cumulative probability reaches `top_p`, `candidates`
+/// must be sorted and normalized.
+fn top_p_filter(candidates: &mut Vec<(u32, f32)>, top_p: f32) {
+    let mut cumulative = 0.0;
+    let mut keep = candidates.len();
+    for (i, (_, p)) in candidates.iter().enumerate() {
+        cumulative += p;
+        if cumulative >= top_p {
+            keep = i + 1;
+            break;
+        }
+    }
+    candidates.truncate(keep);
+}
+
+// https://arxiv.org/abs/2202.00666
+fn typical_filter(candidates: &mut Vec<(u32, f32)>, typical_p: f32) {
+    let entropy: f32 = candidates.iter().map(|(_, p)| -p * p.ln()).sum();
+    let mut by_typicality = candidates.clone();
+    by_typicality.sort_by(|(_, a), (_, b)| {
+        let a = (-a.ln() - entropy).abs();
+        let b = (-b.ln() - entropy).abs();
+        a.total_cmp(&b)
+    });
+    top_p_filter(&mut by_typicality, typical_p);
+    by_typicality.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    *candidates = by_typicality;
+}
@@ -0,0 +1,535 @@
+use anyhow::{Error as E, Result};
+use candle_core::{Device, Tensor};
+use rayon::ThreadPool;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::generation::{Generation, GenerationConfig};
+use crate::models::CausalLm;
+use crate::text_generation::TextGeneration;
+
+/// What a generation does when the conversation fills the model's context window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContextOverflow {
+    /// Stop the generation with [`StopReason::ContextFull`](crate::generation::StopReason).
+    #[default]
+    Error,
+    /// Drop the oldest turns of the conversation, keeping the first one which usually holds the
+    /// system prompt, and stop like `Error` when there are no turns left to drop.
+    TruncateOldest,
+    /// Drop half of the tokens following the first turn, even in the middle of a turn, which also
+    /// works for a single long generation.
+    Shift,
+}
+
+/// A conversation with a model, owning its own KV cache and token history.
+///
+/// Text appended to a session is only fed to the model for the tokens that are not in the KV
+/// cache yet, so follow-up turns do not re-run the prefill for the earlier history.
+pub struct Session {
+    model: Box<dyn CausalLm>,
+    tokens: Vec<u32>,
+    /// The number of tokens from `tokens` that are in the KV cache.
+    index_pos: usize,
+    /// Where each text appended to the conversation starts in `tokens`.
+    turns: Vec<usize>,
+    /// The number of tokens of the first text appended, kept when the context overflows.
+    system_tokens: usize,
+    /// The draft model used for speculative decoding, with its own KV cache.
+    draft: Option<Box<dyn CausalLm>>,
+    /// The number of tokens in the draft model's KV cache, which can run ahead of `tokens` while
+    /// proposing.
+    draft_pos: usize,
+    /// The threads the model runs on, `None` uses the global thread pool.
+    threads: Option<Arc<ThreadPool>>,
+}
+
+impl Session {
+    pub fn new(model: Box<dyn CausalLm>) -> Self {
+        Self::with_draft(model, None)
+    }
+
+    /// A session that also keeps a `draft` model in sync for speculative decoding.
+    pub fn with_draft(model: Box<dyn CausalLm>, draft: Option<Box<dyn CausalLm>>) -> Self {
+        Self {
+            model,
+            tokens: Vec::new(),
+            index_pos: 0,
+            turns: Vec::new(),
+            system_tokens: 0,
+            draft,
+            draft_pos: 0,
+            threads: None,
+        }
+    }
+
+    /// Every token of the conversation so far, including the ones not fed to the model yet.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Clears the conversation and the KV cache.
+    pub fn reset(&mut self) -> Result<(), E> {
+        self.model.reset()?;
+        self.tokens.clear();
+        self.index_pos = 0;
+        self.turns.clear();
+        self.system_tokens = 0;
+        if let Some(draft) = &mut self.draft {
+            draft.reset()?;
+        }
+        self.draft_pos = 0;
+        Ok(())
+    }
+
+    /// Returns a copy of the conversation with its own copy of the KV cache, so that both can be
+    /// continued independently.
+    pub fn fork(&self) -> Result<Self, E> {
+        let draft = match &self.draft {
+            Some(draft) => Some(draft.fork()?),
+            None => None,
+        };
+        Ok(Self {
+            model: self.model.fork()?,
+            tokens: self.tokens.clone(),
+            index_pos: self.index_pos,
+            turns: self.turns.clone(),
+            system_tokens: self.system_tokens,
+            draft,
+            draft_pos: self.draft_pos,
+            threads: self.threads.clone(),
+        })
+    }
+
+    /// Runs the model of this session on its own pool of `threads` threads, or on the pipeline's
+    /// threads when `None`. Forks share the pool.
+    pub fn set_threads(&mut self, threads: Option<usize>) -> Result<(), E> {
+        self.threads = thread_pool(threads)?;
+        Ok(())
+    }
+
+    pub(crate) fn set_thread_pool(&mut self, threads: Option<Arc<ThreadPool>>) {
+        self.threads = threads;
+    }
+
+    /// Appends `text` to the conversation without running the model, returning the number of
+    /// tokens added. Special tokens such as BOS are only added at the start of the conversation.
+    pub fn append(&mut self, pipeline: &TextGeneration, text: &str) -> Result<usize, E> {
+        let tokens = pipeline.encode(text, self.tokens.is_empty())?;
+        self.append_tokens(&tokens);
+        Ok(tokens.len())
+    }
+
+    /// Appends already tokenized text to the conversation as a new turn.
+    pub fn append_tokens(&mut self, tokens: &[u32]) {
+        if tokens.is_empty() {
+            return;
+        }
+        if self.tokens.is_empty() {
+            self.system_tokens = tokens.len();
+        }
+        self.turns.push(self.tokens.len());
+        self.tokens.extend_from_slice(tokens);
+    }
+
+    /// The number of tokens the model can attend to.
+    pub fn context_length(&self) -> usize {
+        self.model.context_length()
+    }
+
+    /// Whether the conversation leaves no room in the context window for another token.
+    pub fn is_full(&self) -> bool {
+        self.tokens.len() >= self.context_length()
+    }
+
+    /// Drops tokens according to `strategy` so that the conversation fits in the context window
+    /// again, returning false when it could not make room. The KV cache is rebuilt from the first
+    /// dropped token on the next forward pass.
+    pub fn make_room(&mut self, strategy: ContextOverflow) -> Result<bool, E> {
+        match strategy {
+            ContextOverflow::Error => Ok(false),
+            ContextOverflow::TruncateOldest => self.drop_oldest_turns(),
+            ContextOverflow::Shift => self.shift(),
+        }
+    }
+
+    /// Drops whole turns after the first one until a quarter of the context is free, never
+    /// dropping the last turn as that is the one being replied to.
+    fn drop_oldest_turns(&mut self) -> Result<bool, E> {
+        if self.turns.len() < 3 {
+            return Ok(false);
+        }
+        let context_length = self.context_length();
+        let target = context_length - context_length / 4;
+        let start = self.turns[1];
+        let last = self.turns.len() - 1;
+        let mut end = 2;
+        while end < last && self.tokens.len() - (self.turns[end] - start) > target {
+            end += 1;
+        }
+        let end = self.turns[end];
+        if self.tokens.len() - (end - start) >= context_length {
+            return Ok(false);
+        }
+        self.remove_tokens(start..end)?;
+        Ok(true)
+    }
+
+    /// Drops the first half of the tokens following the first turn.
+    fn shift(&mut self) -> Result<bool, E> {
+        let keep = self.system_tokens.min(self.tokens.len());
+        let discard = (self.tokens.len() - keep) / 2;
+        if discard == 0 || self.tokens.len() - discard >= self.context_length() {
+            return Ok(false);
+        }
+        self.remove_tokens(keep..keep + discard)?;
+        Ok(true)
+    }
+
+    /// Drops the tokens from `len` on, rolling the KV caches back.
+    pub(crate) fn truncate(&mut self, len: usize) -> Result<(), E> {
+        if len < self.tokens.len() {
+            self.remove_tokens(len..self.tokens.len())?;
+        }
+        Ok(())
+    }
+
+    /// Removes `range` from the conversation and rolls the KV caches back to its start.
+    fn remove_tokens(&mut self, range: Range<usize>) -> Result<(), E> {
+        self.tokens.drain(range.clone());
+        for start in &mut self.turns {
+            if *start >= range.end {
+                *start -= range.len();
+            } else if *start > range.start {
+                *start = range.start;
+            }
+        }
+        self.turns.dedup();
+        rewind(self.model.as_mut(), &mut self.index_pos, range.start)?;
+        if let Some(draft) = &mut self.draft {
+            rewind(draft.as_mut(), &mut self.draft_pos, range.start)?;
+        }
+        Ok(())
+    }
+
+    /// Appends `text` to the conversation and starts generating a reply to it.
+    pub fn generate<'a>(
+        &'a mut self,
+        pipeline: &'a TextGeneration,
+        text: &str,
+        config: GenerationConfig,
+    ) -> Result<Generation<'a>, E> {
+        let prompt_tokens = self.append(pipeline, text)?;
+        Generation::new(pipeline, self.into(), prompt_tokens, config)
+    }
+
+    /// Starts generating a reply to a conversation whose tokens are `tokens`, e.g. a whole chat
+    /// formatted again with a new message. Only the tokens following the ones this session already
+    /// holds are fed to the model, the tokens that differ are first dropped from the KV cache.
+    pub fn generate_tokens<'a>(
+        &'a mut self,
+        pipeline: &'a TextGeneration,
+        tokens: &[u32],
+        config: GenerationConfig,
+    ) -> Result<Generation<'a>, E> {
+        let prompt_tokens = self.replace_tokens(tokens)?;
+        Generation::new(pipeline, self.into(), prompt_tokens, config)
+    }
+
+    /// Makes `tokens` the conversation, keeping the KV cache for the tokens it shares with the
+    /// current one, and returns the number of tokens left to feed to the model.
+    pub(crate) fn replace_tokens(&mut self, tokens: &[u32]) -> Result<usize, E> {
+        if tokens.is_empty() {
+            anyhow::bail!("no tokens to reply to");
+        }
+        // The last token is always fed again so that there are logits to sample from
+        let common = self
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(tokens.len() - 1);
+        if common < self.tokens.len() {
+            self.remove_tokens(common..self.tokens.len())?;
+        }
+        self.append_tokens(&tokens[common..]);
+        Ok(tokens.len() - common)
+    }
+
+    /// Feeds every pending token to the model so that they are in the KV cache.
+    pub fn prefill(&mut self, pipeline: &TextGeneration) -> Result<(), E> {
+        if self.index_pos < self.tokens.len() {
+            self.forward_pending(&pipeline.device)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn push(&mut self, token: u32) {
+        self.tokens.push(token);
+    }
+
+    /// Feeds the tokens that are not in the KV cache yet to the model, returning the logits for
+    /// the last position and the number of tokens fed.
+    pub(crate) fn forward_pending(&mut self, device: &Device) -> Result<(Tensor, usize), E> {
+        let pending = &self.tokens[self.index_pos..];
+        if pending.is_empty() {
+            anyhow::bail!("no pending tokens to feed to the model");
+        }
+        let input = Tensor::new(pending, device)?.unsqueeze(0)?;
+        let model = &mut self.model;
+        let logits = install(&self.threads, || model.forward(&input, self.index_pos))?;
+        let fed = pending.len();
+        self.index_pos = self.tokens.len();
+        Ok((logits, fed))
+    }
+
+    /// Whether `n` draft tokens can be verified, which needs a draft model, models whose KV
+    /// caches can be rolled back and room for the draft tokens in both contexts.
+    pub(crate) fn can_speculate(&self, n: usize) -> bool {
+        let len = self.tokens.len() + n;
+        match &self.draft {
+            Some(draft) => {
+                self.model.can_truncate()
+                    && draft.can_truncate()
+                    && len <= draft.context_length()
+                    && len <= self.model.context_length()
+            }
+            None => false,
+        }
+    }
+
+    /// Lets the draft model greedily propose `n` tokens following the conversation.
+    pub(crate) fn propose(&mut self, device: &Device, n: usize) -> Result<Vec<u32>, E> {
+        let Some(draft) = &mut self.draft else {
+            anyhow::bail!("no draft model");
+        };
+        let mut input = self.tokens[self.draft_pos..].to_vec();
+        if input.is_empty() {
+            anyhow::bail!("no pending tokens to feed to the draft model");
+        }
+        let mut proposed = Vec::with_capacity(n);
+        while proposed.len() < n {
+            let tensor = Tensor::new(input.as_slice(), device)?.unsqueeze(0)?;
+            let logits = install(&self.threads, || draft.forward(&tensor, self.draft_pos))?;
+            self.draft_pos += input.len();
+            let token = logits.squeeze(0)?.argmax(0)?.to_scalar::<u32>()?;
+            proposed.push(token);
+            input = vec![token];
+        }
+        Ok(proposed)
+    }
+
+    /// Feeds the pending tokens followed by `draft` to the model, returning the
+    /// `(seq_len, vocab_size)` logits for every position fed and the number of tokens fed.
+    pub(crate) fn forward_with_draft(
+        &mut self,
+        device: &Device,
+        draft: &[u32],
+    ) -> Result<(Tensor, usize), E> {
+        let mut input = self.tokens[self.index_pos..].to_vec();
+        input.extend_from_slice(draft);
+        let tensor = Tensor::new(input.as_slice(), device)?.unsqueeze(0)?;
+        let model = &mut self.model;
+        let logits = install(&self.threads, || model.forward_all(&tensor, self.index_pos))?;
+        self.index_pos += input.len();
+        Ok((logits.squeeze(0)?, input.len()))
+    }
+
+    /// Drops the rejected draft tokens from the KV caches, keeping every token but the last one,
+    /// which is fed with the next step.
+    pub(crate) fn rollback(&mut self) -> Result<(), E> {
+        let cached = self.tokens.len() - 1;
+        if self.index_pos > cached {
+            self.model.truncate(cached)?;
+            self.index_pos = cached;
+        }
+        if self.draft_pos > cached {
+            if let Some(draft) = &mut self.draft {
+                draft.truncate(cached)?;
+            }
+            self.draft_pos = cached;
+        }
+        Ok(())
+    }
+}
+
+/// Rolls the KV cache of `model`, holding `pos` tokens, back to at most `len` tokens. Models that
+/// cannot truncate their cache start over.
+fn rewind(model: &mut dyn CausalLm, pos: &mut usize, len: usize) -> Result<(), E> {
+    if *pos <= len {
+        return Ok(());
+    }
+    if model.can_truncate() {
+        model.truncate(len)?;
+        *pos = len;
+    } else {
+        model.reset()?;
+        *pos = 0;
+    }
+    Ok(())
+}
+
+/// A pool of `threads` threads for the CPU kernels of a model, `None` for the global pool which
+/// uses every core unless `RAYON_NUM_THREADS` is set.
+pub(crate) fn thread_pool(threads: Option<usize>) -> Result<Option<Arc<ThreadPool>>, E> {
+    let Some(threads) = threads else {
+        return Ok(None);
+    };
+    if threads == 0 {
+        anyhow::bail!("the number of threads must be at least one");
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("jovia-compute-{i}"))
+        .build()?;
+    Ok(Some(Arc::new(pool)))
+}
+
+/// Runs `f` on `threads` when set, so that the parallel kernels it calls stay on that pool.
+fn install<T: Send>(threads: &Option<Arc<ThreadPool>>, f: impl FnOnce() -> T + Send) -> T {
+    match threads {
+        Some(threads) => threads.install(f),
+        None => f(),
+    }
+}
+
+/// Prefilled sessions keyed by the token ids of their prefix, typically a shared system prompt or
+/// persona. Each conversation gets a cheap fork of the cached session so the prefix is only run
+/// through the model once.
+///
+/// Every cached session holds the KV cache of its prefix, so the cache keeps at most `capacity`
+/// of them and drops the least recently used one to make room for a new prefix.
+///
+/// Text appended after the prefix is tokenized on its own, so splitting a prompt at a point where
+/// the tokenizer would merge across the boundary can give slightly different tokens than encoding
+/// the whole prompt at once.
+pub struct PrefixCache {
+    sessions: HashMap<Vec<u32>, CachedPrefix>,
+    capacity: usize,
+    /// Incremented on every use of the cache, to tell which prefix was used last.
+    clock: u64,
+}
+
+struct CachedPrefix {
+    session: Session,
+    last_used: u64,
+}
+
+impl Default for PrefixCache {
+    fn default() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl PrefixCache {
+    /// The number of prefixes kept by [`PrefixCache::new`].
+    pub const DEFAULT_CAPACITY: usize = 8;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache keeping at most `capacity` prefixes, zero caches nothing.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the number of prefixes kept, dropping the least recently used ones over it.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict(capacity);
+    }
+
+    /// Returns a new session starting with `prefix`, prefilling and caching it on first use.
+    pub fn session(&mut self, pipeline: &TextGeneration, prefix: &str) -> Result<Session, E> {
+        let tokens = pipeline.encode(prefix, true)?;
+        self.session_for_tokens(pipeline, tokens)
+    }
+
+    /// Like [`PrefixCache::session`] for an already tokenized prefix.
+    pub fn session_for_tokens(
+        &mut self,
+        pipeline: &TextGeneration,
+        tokens: Vec<u32>,
+    ) -> Result<Session, E> {
+        if let Some(session) = self.get(&tokens) {
+            return session.fork();
+        }
+        let mut session = pipeline.new_session()?;
+        session.append_tokens(&tokens);
+        session.prefill(pipeline)?;
+        let fork = session.fork()?;
+        self.insert(tokens, session);
+        Ok(fork)
+    }
+
+    /// The cached session for the prefix `tokens`, which becomes the most recently used one.
+    pub(crate) fn get(&mut self, tokens: &[u32]) -> Option<&Session> {
+        self.clock += 1;
+        let cached = self.sessions.get_mut(tokens)?;
+        cached.last_used = self.clock;
+        Some(&cached.session)
+    }
+
+    /// Caches the prefilled `session` for the prefix `tokens`.
+    pub(crate) fn insert(&mut self, tokens: Vec<u32>, session: Session) {
+        self.sessions.remove(&tokens);
+        if self.capacity == 0 {
+            return;
+        }
+        self.evict(self.capacity - 1);
+        self.clock += 1;
+        let last_used = self.clock;
+        self.sessions
+            .insert(tokens, CachedPrefix { session, last_used });
+    }
+
+    /// Drops the least recently used prefixes until at most `len` are left.
+    fn evict(&mut self, len: usize) {
+        while self.sessions.len() > len {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(tokens, _)| tokens.clone());
+            if let Some(oldest) = oldest {
+                self.sessions.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn contains(&self, tokens: &[u32]) -> bool {
+        self.sessions.contains_key(tokens)
+    }
+
+    /// Drops the cached session for the prefix `tokens`.
+    pub fn remove(&mut self, tokens: &[u32]) -> bool {
+        self.sessions.remove(tokens).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{Error as E, Result};
+use hf_hub::api::sync::{Api, ApiRepo};
+use hf_hub::{Cache, CacheRepo, Repo, RepoType};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Where the files making up a model are read from.
+pub enum ModelSource {
+    /// A Hugging Face hub repository. Files are served from the local hf-hub cache when present
+    /// and downloaded otherwise.
+    Hub {
+        model_id: String,
+        repo: ApiRepo,
+        cache: CacheRepo,
+    },
+    /// A local directory laid out like a hub repository. This never touches the network.
+    Local(PathBuf),
+}
+
+impl ModelSource {
+    pub fn hub(model_id: String, revision: Option<String>) -> Result<Self, E> {
+        let revision = revision.unwrap_or("main".to_string());
+        let repo = Repo::with_revision(model_id.clone(), RepoType::Model, revision);
+        let cache = Cache::default().repo(repo.clone());
+        let repo = Api::new()?.repo(repo);
+        Ok(Self::Hub {
+            model_id,
+            repo,
+            cache,
+        })
+    }
+
+    pub fn local<P: Into<PathBuf>>(dir: P) -> Result<Self, E> {
+        let dir = dir.into();
+        if !dir.is_dir() {
+            anyhow::bail!("model directory {} does not exist", dir.display());
+        }
+        Ok(Self::Local(dir))
+    }
+
+    /// A human readable identifier for the model, the hub model id or the local directory.
+    pub fn id(&self) -> String {
+        match self {
+            Self::Hub { model_id, .. } => model_id.clone(),
+            Self::Local(dir) => dir.display().to_string(),
+        }
+    }
+
+    /// Resolves `filename` to a path on disk, downloading it first for hub sources.
+    pub fn get(&self, filename: &str) -> Result<PathBuf, E> {
+        match self {
+            Self::Hub { model_id, repo, .. } => repo
+                .get(filename)
+                .map_err(|err| anyhow::anyhow!("cannot fetch {filename} from {model_id}: {err}")),
+            Self::Local(dir) => {
+                let path = dir.join(filename);
+                if !path.is_file() {
+                    anyhow::bail!("{filename} not found in {}", dir.display());
+                }
+                Ok(path)
+            }
+        }
+    }
+
+    /// Like [`ModelSource::get`] for files that not every model ships, `None` when `filename` is
+    /// missing or cannot be fetched.
+    pub fn get_optional(&self, filename: &str) -> Option<PathBuf> {
+        self.get(filename).ok()
+    }
+
+    /// Checks whether `filename` is part of the model without downloading it.
+    pub fn contains(&self, filename: &str) -> bool {
+        match self {
+            Self::Hub { repo, cache, .. } => {
+                // Without network access only the files already in the cache can be seen.
+                cache.get(filename).is_some()
+                    || repo
+                        .info()
+                        .map(|info| info.siblings.iter().any(|s| s.rfilename == filename))
+                        .unwrap_or(false)
+            }
+            Self::Local(dir) => dir.join(filename).is_file(),
+        }
+    }
+
+    /// Resolves the safetensors files for `weights`. When a `{weights}.index.json` index exists
+    /// every shard listed in it is returned, otherwise `weights` is loaded as a single file.
+    pub fn get_safetensors(&self, weights: &str) -> Result<Vec<PathBuf>, E> {
+        let index = if weights.ends_with(".index.json") {
+            weights.to_string()
+        } else {
+            format!("{weights}.index.json")
+        };
+        if index != weights && !self.contains(&index) {
+            return Ok(vec![self.get(weights)?]);
+        }
+        safetensors_index_files(&self.get(&index)?)?
+            .iter()
+            .map(|file| self.get(file))
+            .collect()
+    }
+}
+
+/// Lists the shard files referenced by the `weight_map` of a `model.safetensors.index.json`.
+pub fn safetensors_index_files(json_file: &Path) -> Result<Vec<String>, E> {
+    let json: serde_json::Value =
+        serde_json::from_reader(std::fs::File::open(json_file)?).map_err(E::msg)?;
+    let weight_map = match json.get("weight_map") {
+        None => anyhow::bail!("no weight map in {json_file:?}"),
+        Some(serde_json::Value::Object(map)) => map,
+        Some(_) => anyhow::bail!("weight map in {json_file:?} is not a map"),
+    };
+    let safetensors_files: BTreeSet<String> = weight_map
+        .values()
+        .filter_map(|value| value.as_str().map(str::to_string))
+        .collect();
+    Ok(safetensors_files.into_iter().collect())
+}
+
+/// The on-disk format of a model's weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeightsFormat {
+    /// Full precision safetensors, possibly split into several shards.
+    Safetensors,
+    /// A single quantized GGUF file which carries its own config in its metadata.
+    Gguf,
+    /// A llama2.c checkpoint (e.g. karpathy/tinyllamas `stories15M.bin`) with its config in the
+    /// file header.
+    Llama2c,
+    /// A PyTorch `pytorch_model.bin` checkpoint, which cannot be loaded. Hub repositories usually
+    /// ship the same weights as safetensors.
+    PyTorch,
+}
+
+impl WeightsFormat {
+    /// Picks the format from the file name. `.bin` files are llama2.c checkpoints unless they are
+    /// named like the PyTorch checkpoints of the hub, e.g. `pytorch_model-00001-of-00002.bin`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let name = path.file_name().and_then(|name| name.to_str());
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gguf") => Self::Gguf,
+            Some("bin") if name.is_some_and(|name| name.starts_with("pytorch_model")) => {
+                Self::PyTorch
+            }
+            Some("bin") => Self::Llama2c,
+            _ => Self::Safetensors,
+        }
+    }
+}
+
+pub(crate) const PYTORCH_UNSUPPORTED: &str =
+    "PyTorch .bin checkpoints are not supported, load the model's safetensors or GGUF weights";
+
+/// Explicit paths to the files needed to load a model.
+pub struct ModelFiles {
+    pub tokenizer: PathBuf,
+    /// The `config.json`, only required by formats that do not embed their config.
+    pub config: Option<PathBuf>,
+    pub weights: Vec<PathBuf>,
+    /// The optional `generation_config.json`, read for the end of sequence tokens.
+    pub generation_config: Option<PathBuf>,
+    /// The optional `tokenizer_config.json` next to the tokenizer.
+    pub tokenizer_config: Option<PathBuf>,
+}
+
+impl ModelFiles {
+    /// Builds the file set from explicit paths, checking that every file exists.
+    pub fn new(
+        tokenizer: PathBuf,
+        config: Option<PathBuf>,
+        weights: Vec<PathBuf>,
+    ) -> Result<Self, E> {
+        ensure_file("tokenizer", &tokenizer)?;
+        if let Some(config) = &config {
+            ensure_file("config", config)?;
+        }
+        if weights.is_empty() {
+            anyhow::bail!("no weight files given");
+        }
+        for path in &weights {
+            ensure_file("weights", path)?;
+        }
+        Ok(Self {
+            tokenizer,
+            config,
+            weights,
+            generation_config: None,
+            tokenizer_config: None,
+        })
+    }
+
+    /// Resolves `tokenizer.json`, `config.json` and the `weights` from `source`, including every
+    /// shard when safetensors weights are split. Formats that embed their config do not need a
+    /// `config.json`.
+    pub fn from_source(source: &ModelSource, weights: &str) -> Result<Self, E> {
+        Self::from_sources(source, source, weights)
+    }
+
+    /// Like [`ModelFiles::from_source`] but reads `tokenizer.json` from `tokenizer_source`, for
+    /// repositories that only ship weights.
+    pub fn from_sources(
+        source: &ModelSource,
+        tokenizer_source: &ModelSource,
+        weights: &str,
+    ) -> Result<Self, E> {
+        let (config, weights) = match WeightsFormat::from_path(weights) {
+            WeightsFormat::Safetensors => (
+                Some(source.get("config.json")?),
+                source.get_safetensors(weights)?,
+            ),
+            WeightsFormat::Gguf | WeightsFormat::Llama2c => (None, vec![source.get(weights)?]),
+            WeightsFormat::PyTorch => anyhow::bail!(PYTORCH_UNSUPPORTED),
+        };
+        Ok(Self {
+            tokenizer: tokenizer_source.get("tokenizer.json")?,
+            config,
+            weights,
+            generation_config: source.get_optional("generation_config.json"),
+            tokenizer_config: tokenizer_source.get_optional("tokenizer_config.json"),
+        })
+    }
+
+    pub fn format(&self) -> WeightsFormat {
+        WeightsFormat::from_path(&self.weights[0])
+    }
+
+    /// The `config.json` path, failing for file sets that were resolved without one.
+    pub fn config(&self) -> Result<&Path, E> {
+        match &self.config {
+            Some(config) => Ok(config),
+            None => anyhow::bail!("no config file given"),
+        }
+    }
+}
+
+fn ensure_file(kind: &str, path: &Path) -> Result<(), E> {
+    if !path.is_file() {
+        anyhow::bail!("{kind} file {} does not exist", path.display());
+    }
+    Ok(())
+}
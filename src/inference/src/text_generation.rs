@@ -1,36 +1,103 @@
 use anyhow::{Error as E, Result};
 use candle_core::utils::cuda_is_available;
 use candle_core::{DType, Device, IndexOp, Tensor};
-use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::models::llama as model;
-use hf_hub::{Repo, RepoType};
-use model::{Cache, Config, Llama, LlamaConfig};
+use rayon::ThreadPool;
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokenizers::decoders::DecoderWrapper;
 use tokenizers::Tokenizer;
 
+use crate::bias::LogitBias;
+use crate::generation::{Generation, GenerationConfig, SessionSlot, StopReason};
+use crate::grammar::{Grammar, GrammarState, TokenTrie};
+use crate::lora::LoraAdapter;
+use crate::models::{self, CausalLm};
+use crate::prompts::{ChatMessage, ChatTemplate};
+use crate::sampling::{Sampler, SamplingConfig, TokenLogprobs};
+use crate::session::{self, Session};
+use crate::source::{safetensors_index_files, ModelFiles, ModelSource};
+
 //#[derive(Clone)]
 pub struct TextGeneration {
-    pub model: Llama,
+    pub model: Box<dyn CausalLm>,
     pub model_id: String,
     pub device: Device,
     pub tokenizer: Tokenizer,
     //token_output_stream: TokenOutputStream,
-    pub logits_processor: LogitsProcessor,
+    /// The sampling used by generations that do not specify their own.
+    pub sampling: SamplingConfig,
+    /// Token ids that end a generation, read from the model's config files.
+    pub stop_tokens: Vec<u32>,
+    /// The `chat_template` of the tokenizer config, used to format conversations.
+    pub chat_template: Option<ChatTemplate>,
+    /// Built on the first grammar constrained generation.
+    token_trie: OnceLock<TokenTrie>,
+    /// A small model sharing the tokenizer which proposes tokens for speculative decoding.
+    draft_model: Option<Box<dyn CausalLm>>,
+    /// The threads the sessions started by the pipeline run on, `None` uses every core.
+    threads: Option<Arc<ThreadPool>>,
     pub tokens: Vec<String>,
-    pub cache: Cache,
-    pub config: Config,
 }
 
 impl TextGeneration {
+    /// Loads `which_model` along with its tokenizer and config from a Hugging Face hub repository.
+    /// `tokenizer_id` names a separate hub repository to take `tokenizer.json` from, for weights
+    /// only repositories such as karpathy/tinyllamas.
     pub fn new(
         model_id: String,
         which_model: String,
+        tokenizer_id: Option<String>,
         revision: Option<String>,
         dtype: Option<String>,
         temp: Option<f64>,
         top_p: Option<f64>,
+    ) -> Result<Self, E> {
+        let source = ModelSource::hub(model_id, revision)?;
+        match tokenizer_id {
+            Some(tokenizer_id) => {
+                let tokenizer_source = ModelSource::hub(tokenizer_id, None)?;
+                let files = ModelFiles::from_sources(&source, &tokenizer_source, &which_model)?;
+                Self::from_files(source.id(), files, dtype, temp, top_p)
+            }
+            None => Self::from_source(&source, &which_model, dtype, temp, top_p),
+        }
+    }
+
+    /// Loads a model from a local directory laid out like a hub repository, without any network
+    /// access.
+    pub fn from_local<P: Into<PathBuf>>(
+        model_dir: P,
+        which_model: String,
+        dtype: Option<String>,
+        temp: Option<f64>,
+        top_p: Option<f64>,
+    ) -> Result<Self, E> {
+        let source = ModelSource::local(model_dir)?;
+        Self::from_source(&source, &which_model, dtype, temp, top_p)
+    }
+
+    pub fn from_source(
+        source: &ModelSource,
+        which_model: &str,
+        dtype: Option<String>,
+        temp: Option<f64>,
+        top_p: Option<f64>,
+    ) -> Result<Self, E> {
+        let files = ModelFiles::from_source(source, which_model)?;
+        Self::from_files(source.id(), files, dtype, temp, top_p)
+    }
+
+    /// Loads a model from explicit file paths, without any network access.
+    /// `.gguf` weights are loaded as a quantized Llama, `.bin` weights as a llama2.c checkpoint
+    /// and anything else as safetensors of the architecture named in `config.json`.
+    pub fn from_files(
+        model_id: String,
+        files: ModelFiles,
+        dtype: Option<String>,
+        temp: Option<f64>,
+        top_p: Option<f64>,
     ) -> Result<Self, E> {
         let device = Device::Cpu;
         let dtype = match dtype.as_deref() {
@@ -41,89 +108,404 @@ impl TextGeneration {
             None => DType::F16,
         };
 
-        let (llama, tokenizer_filename, mut cache, config) = {
-            let api = hf_hub::api::sync::Api::new()?;
-            let revision = revision.unwrap_or("main".to_string());
-            let api = api.repo(Repo::with_revision(
-                model_id.clone(),
-                RepoType::Model,
-                revision,
-            ));
-            let tokenizer_filename = api.get("tokenizer.json")?;
-            let config_filename = api.get("config.json")?;
-            let config: LlamaConfig = serde_json::from_slice(&std::fs::read(config_filename)?)?;
-            let config = config.into_config(false);
-            let filenames = vec![api.get("model.safetensors")?];
-            let cache = model::Cache::new(true, dtype, &config, &device)?;
-            let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-
-            (Llama::load(vb, &config)?, tokenizer_filename, cache, config)
-        };
+        let model = models::load(&files, dtype, &device)?;
 
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-        let logits_processor = LogitsProcessor::new(299792458, temp, top_p);
+        let tokenizer = Tokenizer::from_file(&files.tokenizer).map_err(E::msg)?;
+        let stop_tokens = stop_token_ids(&files, &tokenizer)?;
+        let chat_template = match &files.tokenizer_config {
+            Some(path) => ChatTemplate::from_tokenizer_config(path)?,
+            None => None,
+        };
 
         Ok(Self {
-            model: llama,
+            model,
             model_id,
             device,
             tokenizer,
+            stop_tokens,
+            chat_template,
+            token_trie: OnceLock::new(),
+            draft_model: None,
+            threads: None,
             tokens: Vec::new(),
-            logits_processor,
-            cache,
-            config,
+            sampling: SamplingConfig {
+                greedy: temp.is_none(),
+                temperature: temp.unwrap_or(1.0),
+                top_p,
+                ..Default::default()
+            },
         })
     }
 
     pub fn tokenize(&self, input: String) -> Result<Vec<u32>, anyhow::Error> {
+        self.encode(&input, true)
+    }
+
+    pub fn encode(&self, input: &str, add_special_tokens: bool) -> Result<Vec<u32>, E> {
         Ok(self
             .tokenizer
-            .encode(input, true)
+            .encode(input, add_special_tokens)
             .map_err(E::msg)?
             .get_ids()
             .to_vec())
     }
 
+    /// The token ids `text` is written as, both on its own and after a space since most
+    /// tokenizers merge the leading space into the first token of a word.
+    pub fn word_tokens(&self, text: &str) -> Result<Vec<Vec<u32>>, E> {
+        let mut spellings = vec![self.encode(text, false)?];
+        let spaced = self.encode(&format!(" {text}"), false)?;
+        if !spellings.contains(&spaced) {
+            spellings.push(spaced);
+        }
+        spellings.retain(|tokens| !tokens.is_empty());
+        Ok(spellings)
+    }
+
+    /// Decodes `tokens` as a whole, skipping special tokens. Use a [`TokenOutputStream`] to
+    /// decode text token by token, as decoding tokens one at a time loses the leading spaces and
+    /// multi-byte characters that depend on the neighbouring tokens.
     pub fn decode(&self, tokens: &[u32]) -> String {
-        // TODO: Figure out how to avoid cloning the tokenizer
-        let tokenizer = self.tokenizer.clone();
-        let tokenizer = TokenOutputStream::new(tokenizer);
-        tokenizer.decode(tokens).unwrap_or("".to_string())
+        self.tokenizer.decode(tokens, true).unwrap_or_default()
+    }
+
+    /// The bytes of `token` as it reads inside a text, keeping the leading space that decoding it
+    /// on its own strips for SentencePiece tokenizers. Byte fallback tokens such as `<0xE2>` give
+    /// their single byte, which can be part of a multi-byte character.
+    pub fn token_bytes(&self, token: u32) -> Vec<u8> {
+        token_bytes(&self.tokenizer, token)
+    }
+
+    /// The vocabulary arranged for matching against grammars.
+    pub fn token_trie(&self) -> Result<&TokenTrie, E> {
+        if let Some(trie) = self.token_trie.get() {
+            return Ok(trie);
+        }
+        let trie = TokenTrie::new(&self.tokenizer)?;
+        Ok(self.token_trie.get_or_init(|| trie))
+    }
+
+    /// Loads a small model sharing this model's tokenizer, e.g. a karpathy/tinyllamas checkpoint
+    /// for a Llama model, to speed up generation through speculative decoding. Only sessions
+    /// started afterwards use it.
+    ///
+    /// Rejected draft tokens are dropped from the KV caches, so both models must be able to
+    /// truncate theirs, which currently means safetensors Llama and StableLM models or llama2.c
+    /// checkpoints.
+    pub fn load_draft_model(&mut self, files: &ModelFiles) -> Result<(), E> {
+        let tokenizer = Tokenizer::from_file(&files.tokenizer).map_err(E::msg)?;
+        if tokenizer.get_vocab(true) != self.tokenizer.get_vocab(true) {
+            anyhow::bail!("the draft model must use the same tokenizer as the model");
+        }
+        if !self.model.can_truncate() {
+            anyhow::bail!("speculative decoding is not supported for this model");
+        }
+        let draft = models::load(files, DType::F32, &self.device)?;
+        if !draft.can_truncate() {
+            anyhow::bail!("this model cannot be used as a draft model");
+        }
+        self.draft_model = Some(draft);
+        Ok(())
+    }
+
+    pub fn unload_draft_model(&mut self) {
+        self.draft_model = None;
+    }
+
+    /// Loads a PEFT LoRA adapter made for this model from `source`.
+    pub fn load_lora(&self, source: &ModelSource) -> Result<LoraAdapter, E> {
+        LoraAdapter::from_source(source, &self.device)
+    }
+
+    /// Applies `adapter` to the model, replacing the adapter applied before. Only sessions started
+    /// afterwards use it, earlier ones keep the weights they were started with. `merge` adds the
+    /// adapter to the weights, which is faster at the cost of memory for the adapted weights.
+    pub fn apply_lora(&mut self, adapter: &LoraAdapter, merge: bool) -> Result<(), E> {
+        self.model.apply_lora(adapter, merge)
+    }
+
+    /// Goes back to the base model for the sessions started afterwards.
+    pub fn remove_lora(&mut self) -> Result<(), E> {
+        self.model.remove_lora()
+    }
+
+    /// Limits the model to `threads` CPU threads, leaving the other cores to the game, or lets it
+    /// use every core when `None`. Applies to the sessions started afterwards, which can also be
+    /// given their own limit with [`Session::set_threads`].
+    ///
+    /// The limit bounds the threads the CPU kernels of the forward passes run on, such as the
+    /// matrix products and softmax, which are most of the work. These kernels may still split
+    /// their work in as many parts as `RAYON_NUM_THREADS` or the number of cores, the parts then
+    /// wait for one of the `threads` threads. Sampling and tokenizing run on the calling thread,
+    /// and models on a GPU are not affected.
+    pub fn set_threads(&mut self, threads: Option<usize>) -> Result<(), E> {
+        self.threads = session::thread_pool(threads)?;
+        Ok(())
+    }
+
+    /// The number of threads set with [`TextGeneration::set_threads`].
+    pub fn threads(&self) -> Option<usize> {
+        self.threads.as_ref().map(|pool| pool.current_num_threads())
+    }
+
+    /// Starts a new conversation with its own KV cache.
+    pub fn new_session(&self) -> Result<Session, E> {
+        let model = self.model.fork()?;
+        let draft = match &self.draft_model {
+            Some(draft) => Some(draft.fork()?),
+            None => None,
+        };
+        let mut session = Session::with_draft(model, draft);
+        session.set_thread_pool(self.threads.clone());
+        Ok(session)
+    }
+
+    /// Starts generating a completion for `prompt` in a fresh session, returning an iterator over
+    /// the decoded text. Use [`Session::generate`] to keep a conversation going across calls.
+    pub fn generate(&self, prompt: &str, config: GenerationConfig) -> Result<Generation<'_>, E> {
+        let mut session = self.new_session()?;
+        let prompt_tokens = session.append(self, prompt)?;
+        Generation::new(self, SessionSlot::Owned(session), prompt_tokens, config)
+    }
+
+    /// Formats `messages` with the model's chat template and tokenizes them, adding the start of
+    /// an assistant message when `add_generation_prompt` is set. The special tokens the tokenizer
+    /// puts before a text, such as BOS, are only added when the template does not write them
+    /// itself, and the ones put after it are left out.
+    pub fn encode_chat(
+        &self,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+    ) -> Result<Vec<u32>, E> {
+        let Some(template) = &self.chat_template else {
+            anyhow::bail!("the model has no chat template, set `chat_template` to a ChatFormat");
+        };
+        let text = template.render(messages, add_generation_prompt)?;
+        encode_prefixed(&self.tokenizer, &text)
     }
 
+    /// Starts generating the assistant's reply to `messages` in a fresh session, see
+    /// [`TextGeneration::generate`].
+    pub fn generate_chat(
+        &self,
+        messages: &[ChatMessage],
+        config: GenerationConfig,
+    ) -> Result<Generation<'_>, E> {
+        let tokens = self.encode_chat(messages, true)?;
+        let mut session = self.new_session()?;
+        session.append_tokens(&tokens);
+        Generation::new(self, SessionSlot::Owned(session), tokens.len(), config)
+    }
+
+    /// Generates a JSON document for `prompt` constrained to `schema` and deserializes it, for
+    /// example into a struct or a `serde_json::Value`.
+    pub fn generate_json<T: DeserializeOwned>(
+        &self,
+        prompt: &str,
+        schema: &serde_json::Value,
+        mut config: GenerationConfig,
+    ) -> Result<T, E> {
+        config.grammar = Some(Arc::new(Grammar::from_json_schema(schema)?));
+        let (text, summary) = self.generate(prompt, config)?.complete()?;
+        match summary.stop_reason {
+            StopReason::MaxTokens => {
+                anyhow::bail!("max_new_tokens was reached before the JSON document was complete")
+            }
+            StopReason::ContextFull => {
+                anyhow::bail!("the context window was full before the JSON document was complete")
+            }
+            StopReason::Cancelled | StopReason::DeadlineExceeded => {
+                anyhow::bail!(
+                    "the generation was interrupted before the JSON document was complete"
+                )
+            }
+            _ => {}
+        }
+        serde_json::from_str(&text).map_err(|err| anyhow::anyhow!("invalid JSON output: {err}"))
+    }
+
+    /// Feeds the pending tokens of `session` to the model and samples the next token, which is
+    /// appended to the session. Returns the token, the number of tokens fed to the model and,
+    /// when `top_logprobs` is set, the token's log-probability along with that many of the most
+    /// likely alternatives.
+    #[allow(clippy::too_many_arguments)]
     pub fn next_token(
-        &mut self,
-        tokens: &Vec<u32>,
+        &self,
+        session: &mut Session,
+        sampler: &mut Sampler,
+        bias: &LogitBias,
+        grammar: Option<&mut GrammarState>,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        top_logprobs: Option<usize>,
+    ) -> Result<(u32, usize, Option<TokenLogprobs>), anyhow::Error> {
+        let (logits, ctxt_len) = session.forward_pending(&self.device)?;
+        let (next_token, logprobs) = self.sample(
+            &logits.squeeze(0)?,
+            session.tokens(),
+            sampler,
+            bias,
+            grammar,
+            repeat_penalty,
+            repeat_last_n,
+            top_logprobs,
+        )?;
+        session.push(next_token);
+        Ok((next_token, ctxt_len, logprobs))
+    }
+
+    /// Like [`TextGeneration::next_token`] but lets the session's draft model propose
+    /// `draft_tokens` tokens first, which are checked with a single forward pass of the model.
+    ///
+    /// Every token is still sampled from the model's own logits, in order and with the same
+    /// sampler, so the output is the same as with `next_token`. The draft only decides how many
+    /// tokens a forward pass yields: a draft token is accepted when it is the token that was
+    /// sampled at its position. Returns the tokens appended to the session with their
+    /// log-probabilities and the number of draft tokens accepted.
+    #[allow(clippy::too_many_arguments)]
+    pub fn next_tokens_speculative(
+        &self,
+        session: &mut Session,
+        sampler: &mut Sampler,
+        bias: &LogitBias,
+        mut grammar: Option<&mut GrammarState>,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        top_logprobs: Option<usize>,
+        draft_tokens: usize,
+    ) -> Result<(Vec<(u32, Option<TokenLogprobs>)>, usize), E> {
+        let draft = session.propose(&self.device, draft_tokens)?;
+        let (logits, fed) = session.forward_with_draft(&self.device, &draft)?;
+        // The logits of the last pending token predict the first draft token
+        let first = fed - draft.len() - 1;
+        let mut tokens = Vec::new();
+        let mut accepted = 0;
+        for i in 0..=draft.len() {
+            let (token, logprobs) = self.sample(
+                &logits.get(first + i)?,
+                session.tokens(),
+                sampler,
+                bias,
+                grammar.as_deref_mut(),
+                repeat_penalty,
+                repeat_last_n,
+                top_logprobs,
+            )?;
+            session.push(token);
+            tokens.push((token, logprobs));
+            if draft.get(i) != Some(&token) {
+                break;
+            }
+            accepted += 1;
+            let finished = grammar.as_ref().is_some_and(|g| g.is_finished());
+            if finished || self.stop_tokens.contains(&token) {
+                break;
+            }
+        }
+        session.rollback()?;
+        Ok((tokens, accepted))
+    }
+
+    /// Samples a token from the `(vocab_size,)` logits following `tokens`, applying the repeat
+    /// penalty, bias and grammar. The log-probabilities are computed after these are applied.
+    #[allow(clippy::too_many_arguments)]
+    fn sample(
+        &self,
+        logits: &Tensor,
+        tokens: &[u32],
+        sampler: &mut Sampler,
+        bias: &LogitBias,
+        grammar: Option<&mut GrammarState>,
         repeat_penalty: f32,
         repeat_last_n: usize,
-        context_size: usize,
-        context_index: usize,
-    ) -> Result<(u32, usize), anyhow::Error> {
-        let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-        let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-        let logits = self.model.forward(&input, context_index, &mut self.cache)?;
-        let logits = logits.squeeze(0)?;
+        top_logprobs: Option<usize>,
+    ) -> Result<(u32, Option<TokenLogprobs>), E> {
         let logits = if repeat_penalty == 1. {
-            logits
+            logits.clone()
         } else {
             let start_at = tokens.len().saturating_sub(repeat_last_n);
             candle_transformers::utils::apply_repeat_penalty(
-                &logits,
+                logits,
                 repeat_penalty,
                 &tokens[start_at..],
             )?
         };
 
-        let next_token = self.logits_processor.sample(&logits)?;
-        Ok((next_token, ctxt.len()))
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        bias.apply(&mut logits, tokens);
+        if let Some(grammar) = &grammar {
+            grammar.mask(self.token_trie()?, &mut logits, &self.stop_tokens);
+        }
+
+        let next_token = sampler.sample_from(&logits)?;
+        if let Some(grammar) = grammar {
+            if !self.stop_tokens.contains(&next_token) {
+                grammar.accept_token(self.token_trie()?, next_token)?;
+            }
+        }
+        let logprobs = top_logprobs.map(|n| TokenLogprobs::new(&logits, next_token, n));
+        Ok((next_token, logprobs))
+    }
+}
+
+/// Tokenizes `text` with the special tokens the post-processor of `tokenizer` puts before it,
+/// unless the text starts with them already, but without the ones put after it.
+pub(crate) fn encode_prefixed(tokenizer: &Tokenizer, text: &str) -> Result<Vec<u32>, E> {
+    let encode = |add_special_tokens| -> Result<Vec<u32>, E> {
+        let encoding = tokenizer.encode(text, add_special_tokens).map_err(E::msg)?;
+        Ok(encoding.get_ids().to_vec())
+    };
+    let mut tokens = encode(false)?;
+    let with_special = encode(true)?;
+    let prefix_len = (0..=with_special.len().saturating_sub(tokens.len()))
+        .find(|&i| with_special[i..].starts_with(&tokens))
+        .unwrap_or(0);
+    let prefix = &with_special[..prefix_len];
+    if !tokens.starts_with(prefix) {
+        tokens.splice(0..0, prefix.iter().copied());
+    }
+    Ok(tokens)
+}
+
+pub(crate) fn token_bytes(tokenizer: &Tokenizer, token: u32) -> Vec<u8> {
+    let Some(piece) = tokenizer.id_to_token(token) else {
+        return Vec::new();
+    };
+    let hex = piece
+        .strip_prefix("<0x")
+        .and_then(|hex| hex.strip_suffix('>'));
+    if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+        return vec![byte];
+    }
+    if let Some(DecoderWrapper::ByteLevel(_)) = tokenizer.get_decoder() {
+        return piece.chars().filter_map(byte_level_byte).collect();
+    }
+    piece.replace('▁', " ").into_bytes()
+}
+
+/// The byte written as `c` by GPT-2 style byte-level BPE, which keeps the printable bytes as they
+/// are and writes the others as the characters from U+0100 on.
+fn byte_level_byte(c: char) -> Option<u8> {
+    let printable = |byte: &u8| matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    match u8::try_from(c) {
+        Ok(byte) if printable(&byte) => Some(byte),
+        _ => (0..=255)
+            .filter(|byte| !printable(byte))
+            .nth((c as usize).checked_sub(256)?),
     }
 }
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
+///
+/// Every token is decoded along with the tokens before it, so that the text handed out adds up
+/// to the decoding of all the tokens at once: leading spaces are kept and characters spanning
+/// several byte tokens are only returned once complete.
 pub struct TokenOutputStream {
     tokenizer: tokenizers::Tokenizer,
     tokens: Vec<u32>,
+    /// The number of tokens at the start of `tokens` that were decoded before the stream.
+    context: usize,
     prev_index: usize,
     current_index: usize,
 }
@@ -131,11 +513,20 @@ pub struct TokenOutputStream {
 // https://github.com/huggingface/candle/blob/main/candle-examples/src/token_output_stream.rs
 impl TokenOutputStream {
     pub fn new(tokenizer: tokenizers::Tokenizer) -> Self {
+        Self::with_context(tokenizer, &[])
+    }
+
+    /// A stream continuing the text of `context`, e.g. the end of the prompt, which is decoded
+    /// with the first tokens but not returned. Sentencepiece tokenizers drop the leading space
+    /// of the first token of a text, so the first word of a reply only keeps its space when
+    /// decoded after the prompt.
+    pub fn with_context(tokenizer: tokenizers::Tokenizer, context: &[u32]) -> Self {
         Self {
             tokenizer,
-            tokens: Vec::new(),
+            tokens: context.to_vec(),
+            context: context.len(),
             prev_index: 0,
-            current_index: 0,
+            current_index: context.len(),
         }
     }
 
@@ -150,44 +541,40 @@ impl TokenOutputStream {
         }
     }
 
+    /// The text added by the tokens after `current_index`, `None` when there is none.
+    fn new_text(&self) -> Result<Option<String>> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        match text.get(prev_text.len()..) {
+            Some(new_text) if !new_text.is_empty() => Ok(Some(new_text.to_string())),
+            _ => Ok(None),
+        }
+    }
+
     // https://github.com/huggingface/text-generation-inference/blob/5ba53d44a18983a4de32d122f4cb46f4a17d9ef6/server/text_generation_server/models/model.py#L68
     pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
         self.tokens.push(token);
-        let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() && text.chars().last().unwrap().is_alphanumeric() {
-            let text = text.split_at(prev_text.len());
-            self.prev_index = self.current_index;
-            self.current_index = self.tokens.len();
-            Ok(Some(text.1.to_string()))
-        } else {
-            Ok(None)
+        match self.new_text()? {
+            // Byte tokens decode to U+FFFD until the character they are part of is complete
+            Some(text) if !text.ends_with(char::REPLACEMENT_CHARACTER) => {
+                self.prev_index = self.current_index;
+                self.current_index = self.tokens.len();
+                Ok(Some(text))
+            }
+            _ => Ok(None),
         }
     }
 
+    /// The text held back by `next_token`, to be called once the last token was added.
     pub fn decode_rest(&self) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
-        let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() {
-            let text = text.split_at(prev_text.len());
-            Ok(Some(text.1.to_string()))
-        } else {
-            Ok(None)
-        }
+        self.new_text()
     }
 
+    /// The text of every token added to the stream.
     pub fn decode_all(&self) -> Result<String> {
-        self.decode(&self.tokens)
+        let text = self.decode(&self.tokens)?;
+        let context = self.decode(&self.tokens[..self.context])?;
+        Ok(text.get(context.len()..).unwrap_or_default().to_string())
     }
 
     pub fn get_token(&self, token_s: &str) -> Option<u32> {
@@ -200,33 +587,72 @@ impl TokenOutputStream {
 
     pub fn clear(&mut self) {
         self.tokens.clear();
+        self.context = 0;
         self.prev_index = 0;
         self.current_index = 0;
     }
 }
 
+/// End of turn tokens of common chat formats, used when the config files name none.
+const FALLBACK_STOP_TOKENS: [&str; 5] = [
+    "</s>",
+    "<|endoftext|>",
+    "<|im_end|>",
+    "<|eot_id|>",
+    "<end_of_turn>",
+];
+
+/// Collects the end of sequence token ids from `generation_config.json`, `config.json` and
+/// `tokenizer_config.json`, falling back to the well known end of turn tokens in the vocabulary.
+pub fn stop_token_ids(files: &ModelFiles, tokenizer: &Tokenizer) -> Result<Vec<u32>, E> {
+    let mut ids = Vec::new();
+    for path in [&files.generation_config, &files.config]
+        .into_iter()
+        .flatten()
+    {
+        let json = read_json(path)?;
+        match json.get("eos_token_id") {
+            Some(serde_json::Value::Array(values)) => {
+                ids.extend(values.iter().filter_map(|v| v.as_u64()).map(|v| v as u32))
+            }
+            Some(value) => ids.extend(value.as_u64().map(|v| v as u32)),
+            None => {}
+        }
+    }
+    if let Some(path) = &files.tokenizer_config {
+        let json = read_json(path)?;
+        // Either the token itself or an added token object with its `content`.
+        let eos_token = match json.get("eos_token") {
+            Some(serde_json::Value::Object(token)) => token.get("content").and_then(|c| c.as_str()),
+            Some(token) => token.as_str(),
+            None => None,
+        };
+        ids.extend(eos_token.and_then(|token| tokenizer.token_to_id(token)));
+    }
+    if ids.is_empty() {
+        ids.extend(
+            FALLBACK_STOP_TOKENS
+                .iter()
+                .filter_map(|token| tokenizer.token_to_id(token)),
+        );
+    }
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+    Ok(ids)
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value, E> {
+    serde_json::from_reader(std::fs::File::open(path)?)
+        .map_err(|err| anyhow::anyhow!("cannot parse {}: {err}", path.display()))
+}
+
 pub fn hub_load_safetensors(
     repo: &hf_hub::api::sync::ApiRepo,
     json_file: &str,
 ) -> Result<Vec<std::path::PathBuf>, E> {
     // https://github.com/huggingface/candle/blob/5cdd84e0f6365df832a9dbb062ad3a9a34bb65b3/candle-examples/src/lib.rs#L122
     let json_file = repo.get(json_file).map_err(candle_core::Error::wrap)?;
-    let json_file = std::fs::File::open(json_file)?;
-    let json: serde_json::Value = serde_json::from_reader(&json_file).map_err(E::msg)?;
-
-    // Not sure why this works :think:
-    let weight_map = match json.get("weight_map") {
-        None => anyhow::bail!("no weight map in {json_file:?}"),
-        Some(serde_json::Value::Object(map)) => map,
-        Some(_) => anyhow::bail!("weight map in {json_file:?} is not a map"),
-    };
-    let mut safetensors_files = std::collections::HashSet::new();
-    for value in weight_map.values() {
-        if let Some(file) = value.as_str() {
-            safetensors_files.insert(file.to_string());
-        }
-    }
-    let safetensors_files = safetensors_files
+    let safetensors_files = safetensors_index_files(&json_file)?
         .iter()
         .map(|v| repo.get(v).map_err(E::msg))
         .collect::<Result<Vec<_>>>()?;
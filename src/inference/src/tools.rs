@@ -0,0 +1,89 @@
+use anyhow::{Error as E, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::generation::partial_match;
+use crate::prompts::ChatMessage;
+
+/// A function the model can call, described by the JSON Schema of its arguments.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    pub name: String,
+    /// Tells the model what the tool does and when to use it.
+    pub description: String,
+    /// The JSON Schema of the object of arguments the tool takes.
+    pub parameters: serde_json::Value,
+}
+
+impl Tool {
+    pub fn new(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            parameters,
+        }
+    }
+}
+
+/// A call to a [`Tool`] written by the model as
+/// `<tool_call>{"name": ..., "arguments": {...}}</tool_call>`, the format of Hermes and Qwen
+/// function calling models.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// Finds the tool call in a reply of the model, `None` when it did not call a tool. The
+    /// closing tag is optional so that it can be used as a stop sequence.
+    pub fn parse(reply: &str) -> Option<Self> {
+        let (_, call) = reply.split_once(TOOL_CALL_START)?;
+        let call = call.split(TOOL_CALL_END).next().unwrap_or_default();
+        serde_json::from_str(call.trim()).ok()
+    }
+
+    /// The message feeding the `result` of the call back to the model.
+    pub fn response(&self, result: Result<serde_json::Value, E>) -> ChatMessage {
+        let content = match result {
+            Ok(result) => result,
+            Err(err) => serde_json::json!({ "error": err.to_string() }),
+        };
+        let response = serde_json::json!({ "name": self.name, "content": content });
+        // Sent as the user as not every chat template knows of a tool role
+        ChatMessage::user(format!("<tool_response>{response}</tool_response>"))
+    }
+}
+
+pub const TOOL_CALL_START: &str = "<tool_call>";
+pub const TOOL_CALL_END: &str = "</tool_call>";
+
+/// The length of the start of a streamed `reply` that can be shown to the player, leaving out
+/// the tool call and any trailing text that could turn out to be the start of one.
+pub fn visible_len(reply: &str) -> usize {
+    match reply.find(TOOL_CALL_START) {
+        Some(start) => start,
+        None => reply.len() - partial_match(reply, TOOL_CALL_START),
+    }
+}
+
+/// The instructions telling the model which tools it has and how to call them, added to the
+/// system message.
+pub fn tools_prompt(tools: &[Tool]) -> String {
+    let tools: Vec<String> = tools
+        .iter()
+        .map(|tool| serde_json::to_string(tool).unwrap_or_default())
+        .collect();
+    format!(
+        "You can call the following tools, described by their name, description and the JSON \
+         Schema of their arguments:\n{}\n\nTo call a tool, reply with only \
+         {TOOL_CALL_START}{{\"name\": <tool name>, \"arguments\": <arguments object>}}\
+         {TOOL_CALL_END}. The result is then sent to you in <tool_response></tool_response> \
+         tags.",
+        tools.join("\n")
+    )
+}
@@ -1,39 +1,125 @@
-use actix::{Actor, StreamHandler};
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, StreamHandler};
+use actix_web::{rt, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
+use serde_json::json;
+use std::sync::Mutex;
 
 // Inference stuff
-use inference::*;
+use inference::generation::GenerationConfig;
+use inference::text_generation::TextGeneration;
 
 /// Define HTTP actor
-struct WSActor;
+struct WSActor {
+    pipeline: Option<web::Data<Mutex<TextGeneration>>>,
+}
 
 impl Actor for WSActor {
     type Context = ws::WebsocketContext<Self>;
 }
 
+/// A text message for the client, sent to the actor by the thread running a generation.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Frame(String);
+
+impl Handler<Frame> for WSActor {
+    type Result = ();
+
+    fn handle(&mut self, frame: Frame, ctx: &mut Self::Context) {
+        ctx.text(frame.0);
+    }
+}
+
+impl WSActor {
+    /// Replies to `prompt` with a `{"token": ...}` message per chunk of generated text followed by
+    /// a `{"stats": ...}` message, or an `{"error": ...}` message.
+    ///
+    /// Generating blocks for as long as the model runs, so this is called on a blocking thread
+    /// and the messages are sent to the actor at `addr` as they are produced.
+    fn generate(pipeline: &Mutex<TextGeneration>, prompt: &str, addr: &Addr<Self>) {
+        let send = |message: serde_json::Value| addr.do_send(Frame(message.to_string()));
+        // The model runs one generation at a time, later prompts wait here. A generation that
+        // panicked only ever read the model, so the model is still fine to use
+        let pipeline = pipeline.lock().unwrap_or_else(|err| err.into_inner());
+        let mut generation = match pipeline.generate(prompt, GenerationConfig::default()) {
+            Ok(generation) => generation,
+            Err(err) => return send(json!({ "error": err.to_string() })),
+        };
+        for chunk in generation.by_ref() {
+            if !addr.connected() {
+                // The client went away, leave the model to the other connections
+                return;
+            }
+            match chunk {
+                Ok(chunk) => send(json!({ "token": chunk })),
+                Err(err) => send(json!({ "error": err.to_string() })),
+            }
+        }
+        if let Some(summary) = generation.summary() {
+            send(json!({ "stats": summary.to_json() }));
+        }
+    }
+}
+
 /// Handler for ws::Message message
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WSActor {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Text(text)) => ctx.text(format!("Here is your input: {}", text)),
+            Ok(ws::Message::Text(text)) => match &self.pipeline {
+                Some(pipeline) => {
+                    let (pipeline, addr) = (pipeline.clone(), ctx.address());
+                    rt::task::spawn_blocking(move || Self::generate(&pipeline, &text, &addr));
+                }
+                None => ctx.text(format!("Here is your input: {}", text)),
+            },
             Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
             _ => (),
         }
     }
 }
 
-async fn index(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    let resp = ws::start(WSActor {}, &req, stream);
+/// Opens a WebSocket that replies to prompts with the model in the app data, or echoes its input
+/// when the app holds no model.
+async fn index(
+    req: HttpRequest,
+    stream: web::Payload,
+    pipeline: Option<web::Data<Mutex<TextGeneration>>>,
+) -> Result<HttpResponse, Error> {
+    let resp = ws::start(WSActor { pipeline }, &req, stream);
     println!("{:?}", resp);
     resp
 }
 
+/// Loads the model named by the `JOVIA_MODEL_ID`, `JOVIA_WEIGHTS` and `JOVIA_TOKENIZER_ID`
+/// environment variables, `None` when `JOVIA_MODEL_ID` is not set.
+fn load_pipeline() -> std::io::Result<Option<TextGeneration>> {
+    let Ok(model_id) = std::env::var("JOVIA_MODEL_ID") else {
+        return Ok(None);
+    };
+    let which_model =
+        std::env::var("JOVIA_WEIGHTS").unwrap_or_else(|_| "model.safetensors".to_string());
+    let tokenizer_id = std::env::var("JOVIA_TOKENIZER_ID").ok();
+    let pipeline = TextGeneration::new(model_id, which_model, tokenizer_id, None, None, None, None)
+        .map_err(|err| std::io::Error::other(format!("cannot load the model: {err}")))?;
+    Ok(Some(pipeline))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| App::new().route("/chat", web::get().to(index)))
-        .bind(("127.0.0.1", 8089))?
-        .run()
-        .await
-}
\ No newline at end of file
+    let pipeline = load_pipeline()?.map(|pipeline| web::Data::new(Mutex::new(pipeline)));
+    HttpServer::new(move || {
+        let app = match &pipeline {
+            Some(pipeline) => App::new().app_data(pipeline.clone()),
+            None => App::new(),
+        };
+        app.route("/chat", web::get().to(index))
+    })
+    .bind(("127.0.0.1", 8089))?
+    .run()
+    .await
+}